@@ -0,0 +1,101 @@
+/* Starts a player against a server that sets the volume to 50 and switches the player off,
+ * checks the events and status that come of it, then stops the player. Run by tests/c_api.rs
+ * with a fake server, or by hand:
+ *
+ * cc c/storm_test.c -Iinclude -Ltarget/release -lstorm -lpthread -o storm_test
+ * LD_LIBRARY_PATH=target/release ./storm_test SERVER[:PORT]
+ */
+
+#include <pthread.h>
+#include <stdio.h>
+#include <string.h>
+#include <unistd.h>
+
+#include "storm.h"
+
+/* Ten seconds, in steps of 10ms */
+#define TIMEOUT 1000
+
+struct events {
+    pthread_mutex_t lock;
+    int volume;
+    int power_off;
+    int stopped;
+    int late;
+};
+
+static void on_event(const char *event, const char *detail, void *user_data) {
+    struct events *events = user_data;
+
+    pthread_mutex_lock(&events->lock);
+    if (events->stopped) {
+        events->late = 1;
+    } else if (strcmp(event, "volume") == 0 && strcmp(detail, "50") == 0) {
+        events->volume = 1;
+    } else if (strcmp(event, "power_off") == 0) {
+        events->power_off = 1;
+    }
+    pthread_mutex_unlock(&events->lock);
+}
+
+static int seen(struct events *events, int *flag) {
+    int result;
+
+    pthread_mutex_lock(&events->lock);
+    result = *flag;
+    pthread_mutex_unlock(&events->lock);
+    return result;
+}
+
+int main(int argc, char **argv) {
+    struct events events = {PTHREAD_MUTEX_INITIALIZER, 0, 0, 0, 0};
+    StormStatus status;
+    StormPlayer *player;
+    int i;
+
+    if (argc != 2) {
+        fprintf(stderr, "Usage: %s SERVER[:PORT]\n", argv[0]);
+        return 2;
+    }
+
+    player = storm_start(argv[1], "Storm C", "gst#fakesink", on_event, &events);
+    if (player == NULL) {
+        fprintf(stderr, "Unable to start player\n");
+        return 1;
+    }
+
+    for (i = 0; i < TIMEOUT && !seen(&events, &events.power_off); i++) {
+        usleep(10000);
+    }
+    if (!seen(&events, &events.volume)) {
+        fprintf(stderr, "No volume event\n");
+        return 1;
+    }
+    if (!seen(&events, &events.power_off)) {
+        fprintf(stderr, "No power off event\n");
+        return 1;
+    }
+
+    if (storm_status(player, &status) != 0) {
+        fprintf(stderr, "Player is not connected\n");
+        return 1;
+    }
+    if (status.volume != 50 || status.power != 0) {
+        fprintf(stderr, "Status has volume %u and power %d\n", status.volume, status.power);
+        return 1;
+    }
+
+    storm_stop(player);
+
+    /* Nothing may use the events once the player has stopped */
+    pthread_mutex_lock(&events.lock);
+    events.stopped = 1;
+    pthread_mutex_unlock(&events.lock);
+    usleep(100000);
+    if (seen(&events, &events.late)) {
+        fprintf(stderr, "Callback ran after the player stopped\n");
+        return 1;
+    }
+
+    return 0;
+}
@@ -0,0 +1,81 @@
+#ifndef STORM_H
+#define STORM_H
+
+/* Generated by cbindgen from src/ffi.rs, do not edit */
+
+#include <stdint.h>
+
+#define STORM_STATE_STOP 0
+
+#define STORM_STATE_PLAY 1
+
+#define STORM_STATE_PAUSE 2
+
+/**
+ * An opaque handle to a running player.
+ */
+typedef struct StormPlayer StormPlayer;
+
+typedef struct {
+  int state;
+  int power;
+  unsigned int volume;
+  unsigned int elapsed_milliseconds;
+  unsigned int buffer_fullness;
+  unsigned int output_buffer_fullness;
+} StormStatus;
+
+/**
+ * Called from a thread of the player's for every event, with details such as the
+ * volume, the error or the track as "artist - title - album". Neither string is valid
+ * after the callback returns.
+ */
+typedef void (*StormEventCallback)(const char *event, const char *detail, void *user_data);
+
+/**
+ * Switch the player on if power is non-zero, otherwise off, returning 0 on success.
+ */
+int storm_power(StormPlayer *player, int power);
+
+/**
+ * Returns 0 on success.
+ */
+int storm_pause(StormPlayer *player);
+
+/**
+ * Returns 0 on success.
+ */
+int storm_resume(StormPlayer *player);
+
+/**
+ * Set the volume from 0 to 100, returning 0 on success.
+ */
+int storm_set_volume(StormPlayer *player, unsigned int volume);
+
+/**
+ * Start a player, returning NULL if it could not be started.
+ *
+ * The server is an IPv4 address, with a port if it is not the usual one, or NULL to look
+ * for one. The output is given as for the --output option, e.g. "alsa#hw:0,0", or NULL for
+ * the default.
+ *
+ * The callback is not called again once storm_stop has returned.
+ */
+StormPlayer *storm_start(const char *server,
+                         const char *name,
+                         const char *output,
+                         StormEventCallback callback,
+                         void *user_data);
+
+/**
+ * Fill in the status, returning 0 on success.
+ */
+int storm_status(StormPlayer *player, StormStatus *status);
+
+/**
+ * Stop the player and free it, the handle must not be used again. Once this returns the
+ * callback has finished with the user data.
+ */
+void storm_stop(StormPlayer *player);
+
+#endif /* STORM_H */
@@ -0,0 +1,311 @@
+use actix;
+use actix::{Actor, Arbiter, AsyncContext, Context};
+use bytes::BytesMut;
+use futures::{future, Future, Stream};
+use serde_json;
+use tokio_codec::{Decoder, FramedRead};
+use tokio_io;
+use tokio_io::AsyncRead;
+use tokio_tcp::{TcpListener, TcpStream};
+
+use attach;
+use player;
+use proto;
+
+use std::io;
+use std::net::SocketAddr;
+
+// Requests larger than this are not something we would ever expect
+const MAX_REQUEST_SIZE: usize = 8192;
+
+#[derive(Serialize)]
+pub struct Status {
+    pub name: String,
+    pub server: String,
+    pub state: &'static str,
+    pub power: bool,
+    pub volume: u8,
+    pub elapsed_milliseconds: u32,
+    pub buffer_size: u32,
+    pub buffer_fullness: u32,
+    pub output_buffer_fullness: u32,
+    pub bytes_received: u64,
+    pub signal_strength: u16,
+    pub output_underruns: u32,
+    pub input_underruns: u32,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+}
+
+pub struct GetStatus;
+
+impl actix::Message for GetStatus {
+    type Result = Status;
+}
+
+#[derive(Serialize)]
+pub struct Visualiser {
+    pub mode: &'static str,
+    pub levels: Vec<f64>,
+    pub spectrum: Vec<f32>,
+}
+
+pub struct GetVisualiser;
+
+impl actix::Message for GetVisualiser {
+    type Result = Visualiser;
+}
+
+pub enum Command {
+    Pause,
+    Resume,
+    Volume(u8),
+    Power(bool),
+    Reconnect,
+    Output(player::AudioDevice),
+}
+
+// Only accepted once it has been passed on to the player, otherwise the reason why not
+impl actix::Message for Command {
+    type Result = Result<(), String>;
+}
+
+pub struct Listen(pub SocketAddr);
+
+impl actix::Message for Listen {
+    type Result = ();
+}
+
+// For anything outside of the actors that wants to talk to the current connection
+pub struct GetProto;
+
+impl actix::Message for GetProto {
+    type Result = Option<actix::Addr<proto::Proto>>;
+}
+
+#[derive(Default)]
+pub struct Api {
+    proto: attach::Connection,
+}
+
+impl Actor for Api {
+    type Context = Context<Self>;
+}
+
+impl actix::Supervised for Api {}
+
+impl actix::SystemService for Api {}
+
+impl actix::Handler<Listen> for Api {
+    type Result = ();
+
+    fn handle(&mut self, msg: Listen, ctx: &mut Context<Self>) {
+        match TcpListener::bind(&msg.0) {
+            Ok(listener) => {
+                info!("Control API listening on: {}", msg.0);
+                ctx.add_stream(listener.incoming());
+            }
+            Err(e) => error!("Unable to start control API on {}: {}", msg.0, e),
+        }
+    }
+}
+
+impl actix::Handler<attach::Attach> for Api {
+    type Result = ();
+
+    fn handle(&mut self, msg: attach::Attach, _ctx: &mut Context<Self>) {
+        self.proto.attach(msg);
+    }
+}
+
+impl actix::Handler<GetProto> for Api {
+    type Result = Option<actix::Addr<proto::Proto>>;
+
+    fn handle(&mut self, _msg: GetProto, _ctx: &mut Context<Self>) -> Self::Result {
+        self.proto.current()
+    }
+}
+
+impl actix::StreamHandler<TcpStream, io::Error> for Api {
+    fn handle(&mut self, stream: TcpStream, _ctx: &mut Context<Self>) {
+        let proto = self.proto.current();
+        let (r, w) = stream.split();
+        Arbiter::spawn(
+            FramedRead::new(r, RequestCodec)
+                .into_future()
+                .map_err(|(e, _)| e)
+                .and_then(move |(request, _)| respond(request, proto))
+                .and_then(move |(code, body)| {
+                    tokio_io::io::write_all(w, http_response(code, &body).into_bytes())
+                })
+                .map(|_| ())
+                .map_err(|e| warn!("Control API error: {}", e)),
+        );
+    }
+
+    fn error(&mut self, err: io::Error, _ctx: &mut Context<Self>) -> actix::Running {
+        warn!("Control API connection error: {}", err);
+        actix::Running::Continue
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+}
+
+struct RequestCodec;
+
+impl Decoder for RequestCodec {
+    type Item = Request;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Request>> {
+        let end = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(end) => end,
+            None if buf.len() > MAX_REQUEST_SIZE => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Request too large",
+                ))
+            }
+            None => return Ok(None),
+        };
+
+        let head = buf.split_to(end + 4);
+        let head = String::from_utf8_lossy(&head);
+        let mut request_line = head.lines().nth(0).unwrap_or("").split_whitespace();
+        match (request_line.next(), request_line.next()) {
+            (Some(method), Some(path)) => Ok(Some(Request {
+                method: method.to_uppercase(),
+                path: path.to_owned(),
+            })),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Bad request")),
+        }
+    }
+}
+
+fn respond(
+    request: Option<Request>,
+    proto: Option<actix::Addr<proto::Proto>>,
+) -> Box<dyn Future<Item = (u16, String), Error = io::Error>> {
+    let (request, proto) = match (request, proto) {
+        (Some(request), Some(proto)) => (request, proto),
+        (Some(_), None) => return Box::new(future::ok((503, error_body("Not connected")))),
+        (None, _) => return Box::new(future::ok((400, error_body("Bad request")))),
+    };
+
+    let path: Vec<&str> = request
+        .path
+        .split('?')
+        .nth(0)
+        .unwrap_or("")
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+
+    if request.method == "GET" {
+        return match path.as_slice() {
+            ["status"] => Box::new(
+                proto
+                    .send(GetStatus)
+                    .map(|status| match serde_json::to_string(&status) {
+                        Ok(body) => (200, body),
+                        Err(e) => (500, error_body(&e.to_string())),
+                    })
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string())),
+            ),
+            ["visualiser"] => Box::new(
+                proto
+                    .send(GetVisualiser)
+                    .map(|visualiser| match serde_json::to_string(&visualiser) {
+                        Ok(body) => (200, body),
+                        Err(e) => (500, error_body(&e.to_string())),
+                    })
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string())),
+            ),
+            _ => Box::new(future::ok((404, error_body("Not found")))),
+        };
+    }
+
+    if request.method != "POST" {
+        return Box::new(future::ok((405, error_body("Method not allowed"))));
+    }
+
+    let command = match path.as_slice() {
+        ["pause"] => Command::Pause,
+        ["resume"] => Command::Resume,
+        ["power", "on"] => Command::Power(true),
+        ["power", "off"] => Command::Power(false),
+        ["volume", level] => match level.parse::<u8>() {
+            Ok(level) if level <= 100 => Command::Volume(level),
+            _ => return Box::new(future::ok((400, error_body("Volume must be 0 to 100")))),
+        },
+        ["reconnect"] => Command::Reconnect,
+        // The device is given as for --output, with any '/' or '#' percent-encoded
+        ["output", spec] => {
+            let output = match percent_decode(spec) {
+                Some(spec) => player::AudioDevice::from(spec.split('#').collect::<Vec<&str>>()),
+                None => return Box::new(future::ok((400, error_body("Bad output device")))),
+            };
+            match output.check() {
+                Ok(_) => Command::Output(output),
+                Err(e) => return Box::new(future::ok((400, error_body(&e)))),
+            }
+        }
+        _ => return Box::new(future::ok((404, error_body("Not found")))),
+    };
+
+    info!("Control API request: {}", request.path);
+    Box::new(
+        proto
+            .send(command)
+            .map(|result| match result {
+                Ok(()) => (200, String::from("{\"result\":\"ok\"}")),
+                Err(e) => (409, error_body(&e)),
+            })
+            .or_else(|_| future::ok((503, error_body("Not connected")))),
+    )
+}
+
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut bytes = Vec::new();
+    let mut chars = segment.bytes();
+    while let Some(c) = chars.next() {
+        if c == b'%' {
+            let hex = [chars.next()?, chars.next()?];
+            bytes.push(u8::from_str_radix(::std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(c);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn error_body(error: &str) -> String {
+    json!({ "error": error }).to_string()
+}
+
+fn http_response(code: u16, body: &str) -> String {
+    let reason = match code {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    };
+
+    format!(
+        "HTTP/1.1 {} {}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        code,
+        reason,
+        body.len(),
+        body
+    )
+}
@@ -0,0 +1,63 @@
+use actix;
+use actix::dev::ToEnvelope;
+use actix::{Context, SystemService};
+
+use api;
+use input;
+#[cfg(feature = "mpris")]
+use mpris;
+use mqtt;
+use proto;
+
+// Sent by each new connection to a server so requests go to the right place
+pub struct Attach(pub actix::Addr<proto::Proto>);
+
+impl actix::Message for Attach {
+    type Result = ();
+}
+
+// The connection a service passes its requests on to, which changes when the server asks us to
+// move
+#[derive(Default)]
+pub struct Connection(Option<actix::Addr<proto::Proto>>);
+
+impl Connection {
+    pub fn attach(&mut self, msg: Attach) {
+        self.0 = Some(msg.0);
+    }
+
+    pub fn current(&self) -> Option<actix::Addr<proto::Proto>> {
+        self.0.clone()
+    }
+
+    // Anything sent before there is a connection is dropped
+    pub fn forward<M>(&self, msg: M)
+    where
+        M: actix::Message + Send + 'static,
+        M::Result: Send,
+        proto::Proto: actix::Handler<M>,
+        Context<proto::Proto>: ToEnvelope<proto::Proto, M>,
+    {
+        if let Some(ref proto) = self.0 {
+            proto.do_send(msg);
+        }
+    }
+}
+
+// Attach a new connection to each service the player was started with
+pub fn attach(proto: &actix::Addr<proto::Proto>, options: &proto::Options) {
+    // Always attached, as an embedding application talks to us this way too
+    api::Api::from_registry().do_send(Attach(proto.clone()));
+    if options.input.is_some() {
+        input::Input::from_registry().do_send(Attach(proto.clone()));
+    }
+    if options.mqtt_broker.is_some() {
+        mqtt::Mqtt::from_registry().do_send(Attach(proto.clone()));
+    }
+    #[cfg(feature = "mpris")]
+    {
+        if options.mpris {
+            mpris::Mpris::from_registry().do_send(Attach(proto.clone()));
+        }
+    }
+}
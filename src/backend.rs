@@ -0,0 +1,176 @@
+use actix;
+use actix::{Actor, Context};
+use gst::prelude::ClockExt;
+
+use player::{self, AudioDevice, DeviceRelease, PlayerControl, PlayerMessages};
+
+use std::sync::{Arc, Mutex};
+
+/// What plays the audio. Each connection to a server starts an output, which is sent
+/// `PlayerControl`s and reports back to the server connection with `PlayerMessages`.
+pub trait Backend: Send + Sync {
+    /// Get ready to play, before any output is started.
+    fn init(&self) -> Result<(), String>;
+
+    /// Make sure the output can be used, so that mistakes are found before playing.
+    fn check(&self, output_device: &AudioDevice) -> Result<(), String>;
+
+    fn start(
+        &self,
+        proto: actix::Recipient<PlayerMessages>,
+        output_device: &AudioDevice,
+        device_release: DeviceRelease,
+    ) -> actix::Recipient<PlayerControl>;
+
+    /// Milliseconds of the clock the audio is played by, which the server's timings use.
+    fn clock_time(&self) -> u64;
+
+    fn max_sample_rate(&self, output_device: &AudioDevice) -> Option<u32>;
+
+    /// Format codes, as the server knows them, of the streams that can be decoded.
+    fn decode_caps(&self) -> Vec<String>;
+}
+
+/// Plays through a GStreamer pipeline.
+pub struct Gstreamer;
+
+impl Backend for Gstreamer {
+    fn init(&self) -> Result<(), String> {
+        player::init()
+    }
+
+    fn check(&self, output_device: &AudioDevice) -> Result<(), String> {
+        output_device.check()
+    }
+
+    fn start(
+        &self,
+        proto: actix::Recipient<PlayerMessages>,
+        output_device: &AudioDevice,
+        device_release: DeviceRelease,
+    ) -> actix::Recipient<PlayerControl> {
+        player::Player::new(proto, output_device.clone(), device_release)
+            .start()
+            .recipient()
+    }
+
+    fn clock_time(&self) -> u64 {
+        player::clock().get_time().mseconds().unwrap_or(0)
+    }
+
+    fn max_sample_rate(&self, output_device: &AudioDevice) -> Option<u32> {
+        player::max_sample_rate(output_device)
+    }
+
+    fn decode_caps(&self) -> Vec<String> {
+        player::decode_caps()
+    }
+}
+
+/// Plays nothing, but keeps every control it is sent and answers as a player would,
+/// so that handling of the protocol can be checked without any audio.
+#[derive(Clone, Default)]
+pub struct Recorder {
+    controls: Arc<Mutex<Vec<PlayerControl>>>,
+    time: Arc<Mutex<u64>>,
+    proto: Arc<Mutex<Option<actix::Recipient<PlayerMessages>>>>,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Recorder::default()
+    }
+
+    /// The controls sent so far, oldest first.
+    pub fn controls(&self) -> Vec<PlayerControl> {
+        self.controls.lock().unwrap().clone()
+    }
+
+    /// The clock only moves when it is told to.
+    pub fn advance(&self, millis: u64) {
+        *self.time.lock().unwrap() += millis;
+    }
+
+    /// Say something to the server connection as the player would, such as that a stream has
+    /// ended. It goes to the connection started most recently.
+    pub fn send(&self, msg: PlayerMessages) {
+        if let Some(ref proto) = *self.proto.lock().unwrap() {
+            let _ = proto.do_send(msg);
+        }
+    }
+}
+
+impl Backend for Recorder {
+    fn init(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn check(&self, _output_device: &AudioDevice) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn start(
+        &self,
+        proto: actix::Recipient<PlayerMessages>,
+        _output_device: &AudioDevice,
+        _device_release: DeviceRelease,
+    ) -> actix::Recipient<PlayerControl> {
+        *self.proto.lock().unwrap() = Some(proto.clone());
+        Recording {
+            proto: proto,
+            controls: self.controls.clone(),
+        }
+        .start()
+        .recipient()
+    }
+
+    fn clock_time(&self) -> u64 {
+        *self.time.lock().unwrap()
+    }
+
+    fn max_sample_rate(&self, _output_device: &AudioDevice) -> Option<u32> {
+        Some(48_000)
+    }
+
+    fn decode_caps(&self) -> Vec<String> {
+        vec!["flc".to_owned(), "pcm".to_owned(), "mp3".to_owned()]
+    }
+}
+
+struct Recording {
+    proto: actix::Recipient<PlayerMessages>,
+    controls: Arc<Mutex<Vec<PlayerControl>>>,
+}
+
+impl Actor for Recording {
+    type Context = Context<Self>;
+}
+
+impl actix::Handler<PlayerControl> for Recording {
+    type Result = ();
+
+    fn handle(&mut self, msg: PlayerControl, _ctx: &mut Context<Self>) {
+        debug!("Recording: {:?}", msg);
+
+        // Only what a player says straight away, anything else is up to whoever is testing
+        let replies = match msg {
+            PlayerControl::Stream { .. } => {
+                vec![PlayerMessages::Established, PlayerMessages::Start]
+            }
+            PlayerControl::Stop => vec![PlayerMessages::Flushed],
+            PlayerControl::Pause(false) => vec![PlayerMessages::Paused],
+            PlayerControl::Unpause(false) | PlayerControl::UnpauseAt { quiet: false, .. } => {
+                vec![PlayerMessages::Unpaused]
+            }
+            PlayerControl::Output(ref output_device) => {
+                vec![PlayerMessages::OutputChanged(output_device.clone())]
+            }
+            _ => Vec::new(),
+        };
+
+        self.controls.lock().unwrap().push(msg);
+        for reply in replies {
+            let _ = self.proto.do_send(reply);
+        }
+    }
+}
@@ -6,6 +6,9 @@ use std::convert::From;
 use std::io;
 use std::net::Ipv4Addr;
 
+// Squeezebox remotes use 16 bit NEC codes, sent as 32 bits with their complements
+const IR_BITS: u8 = 16;
+
 pub struct SlimCodec;
 
 impl tokio_codec::Encoder for SlimCodec {
@@ -62,6 +65,11 @@ pub enum ClientMessage {
     },
     Bye(u8),
     Name(String),
+    Ir {
+        time: u32,
+        format: u8,
+        code: u32,
+    },
 }
 
 #[derive(Clone, Copy)]
@@ -70,7 +78,7 @@ pub struct StatData {
     pub buffer_size: u32,
     pub fullness: u32,
     pub bytes_received: u64,
-    sig_strength: u16,
+    pub sig_strength: u16,
     pub jiffies: u32,
     output_buffer_size: u32,
     pub output_buffer_fullness: u32,
@@ -78,7 +86,7 @@ pub struct StatData {
     voltage: u16,
     pub elapsed_milliseconds: u32,
     pub timestamp: u32,
-    error_code: u16,
+    pub error_code: u16,
 }
 
 impl StatData {
@@ -123,6 +131,7 @@ pub enum ServerMessage {
         replay_gain: f64,
         server_port: u16,
         server_ip: Ipv4Addr,
+        use_ssl: bool,
         http_headers: String,
     },
     Gain(f64, f64),
@@ -134,6 +143,30 @@ pub enum ServerMessage {
     Setname(String),
     Unknownsetd(u8),
     Skip(u32),
+    Vfdc(Vec<u16>),
+    Grfe {
+        offset: u16,
+        data: Vec<u8>,
+    },
+    Grfb(i16),
+    Grfs {
+        direction: u8,
+        pause: u32,
+        speed: u32,
+        by: u16,
+        mode: u16,
+        width: u16,
+        offset: u16,
+        data: Vec<u8>,
+    },
+    Grfg {
+        width: u16,
+        data: Vec<u8>,
+    },
+    Visu {
+        which: u8,
+        params: Vec<u32>,
+    },
     Unrecognised(String),
     Error,
 }
@@ -196,6 +229,15 @@ impl From<ClientMessage> for BytesMut {
                 buf.put_u8(0);
                 buf.put(name.as_bytes());
             }
+
+            ClientMessage::Ir { time, format, code } => {
+                info!("Sending IR code: {:#010x}", code);
+                buf.put("IR  ".as_bytes());
+                buf.put_u32_be(time);
+                buf.put_u8(format);
+                buf.put_u8(IR_BITS);
+                buf.put_u32_be(code);
+            }
         }
 
         let mut msg_length = Vec::new();
@@ -258,6 +300,8 @@ impl From<BytesMut> for ServerMessage {
                             replay_gain: replay_gain,
                             server_port: src[18..20].into_buf().get_u16_be(),
                             server_ip: Ipv4Addr::from(src[20..24].into_buf().get_u32_be()),
+                            // Bit 5 of the flags asks for the stream to be fetched over TLS
+                            use_ssl: src[11] & 0x20 != 0,
                             http_headers: http_headers,
                         }
                     }
@@ -307,7 +351,184 @@ impl From<BytesMut> for ServerMessage {
                 }
             }
 
+            "vfdc" => ServerMessage::Vfdc(
+                src.chunks(2)
+                    .filter(|word| word.len() == 2)
+                    .map(|word| word.into_buf().get_u16_be())
+                    .collect(),
+            ),
+
+            "grfe" => {
+                if src.len() < 4 {
+                    return ServerMessage::Error;
+                }
+                ServerMessage::Grfe {
+                    offset: src[0..2].into_buf().get_u16_be(),
+                    data: src[4..].to_vec(),
+                }
+            }
+
+            "grfb" => {
+                if src.len() < 2 {
+                    return ServerMessage::Error;
+                }
+                ServerMessage::Grfb(src[0..2].into_buf().get_i16_be())
+            }
+
+            "grfs" => {
+                if src.len() < 18 {
+                    return ServerMessage::Error;
+                }
+                // The screen comes first, then which way to scroll. A long frame is sent in
+                // several parts, each saying where in the frame it goes
+                ServerMessage::Grfs {
+                    direction: src[1],
+                    pause: src[2..6].into_buf().get_u32_be(),
+                    speed: src[6..10].into_buf().get_u32_be(),
+                    by: src[10..12].into_buf().get_u16_be(),
+                    mode: src[12..14].into_buf().get_u16_be(),
+                    width: src[14..16].into_buf().get_u16_be(),
+                    offset: src[16..18].into_buf().get_u16_be(),
+                    data: src[18..].to_vec(),
+                }
+            }
+
+            // The screen, then how much of it scrolls, then what stays still behind it
+            "grfg" => {
+                if src.len() < 4 {
+                    return ServerMessage::Error;
+                }
+                ServerMessage::Grfg {
+                    width: src[2..4].into_buf().get_u16_be(),
+                    data: src[4..].to_vec(),
+                }
+            }
+
+            "visu" => {
+                if src.len() < 2 {
+                    return ServerMessage::Error;
+                }
+                ServerMessage::Visu {
+                    which: src[0],
+                    params: src[2..]
+                        .chunks(4)
+                        .take(src[1] as usize)
+                        .filter(|param| param.len() == 4)
+                        .map(|param| param.into_buf().get_u32_be())
+                        .collect(),
+                }
+            }
+
             cmd @ _ => ServerMessage::Unrecognised(cmd.to_owned()),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_codec::Decoder;
+
+    // Frames as the server sends them, with the length first
+    fn decode(command: &str, payload: &[u8]) -> io::Result<Option<ServerMessage>> {
+        let mut frame = Vec::new();
+        frame.put_u16_be((payload.len() + 4) as u16);
+        frame.put(command.as_bytes());
+        frame.put(payload);
+        SlimCodec.decode(&mut BytesMut::from(frame))
+    }
+
+    // What the server packs as 'CCNNnnnn': screen, direction, pause, speed, pixels to scroll by,
+    // mode, width and offset, then the part of the frame
+    fn grfs(direction: u8, mode: u16, width: u16, offset: u16, data: &[u8]) -> Vec<u8> {
+        let mut payload = Vec::new();
+        payload.put_u8(0);
+        payload.put_u8(direction);
+        payload.put_u32_be(3600);
+        payload.put_u32_be(33);
+        payload.put_u16_be(2);
+        payload.put_u16_be(mode);
+        payload.put_u16_be(width);
+        payload.put_u16_be(offset);
+        payload.put(data);
+        payload
+    }
+
+    #[test]
+    fn scroll_frame_parts() {
+        match decode("grfs", &grfs(1, 0, 448, 0, &[0xff; 1280])) {
+            Ok(Some(ServerMessage::Grfs {
+                direction: 1,
+                pause: 3600,
+                speed: 33,
+                by: 2,
+                mode: 0,
+                width: 448,
+                offset: 0,
+                ref data,
+            })) if data.len() == 1280 => (),
+            _ => panic!("First part of the scroll frame parsed wrongly"),
+        }
+
+        match decode("grfs", &grfs(2, 2, 448, 1280, &[0x0f; 768])) {
+            Ok(Some(ServerMessage::Grfs {
+                direction: 2,
+                mode: 2,
+                width: 448,
+                offset: 1280,
+                ref data,
+                ..
+            })) if data.len() == 768 && data[0] == 0x0f => (),
+            _ => panic!("Second part of the scroll frame parsed wrongly"),
+        }
+    }
+
+    #[test]
+    fn scroll_start_has_window() {
+        // Packed as 'nn': screen and the width that scrolls, then the background
+        let mut payload = Vec::new();
+        payload.put_u16_be(0);
+        payload.put_u16_be(280);
+        payload.put(&[0xaa; 1280][..]);
+        match decode("grfg", &payload) {
+            Ok(Some(ServerMessage::Grfg { width: 280, ref data }))
+                if data.len() == 1280 && data[0] == 0xaa => (),
+            _ => panic!("Scroll start parsed wrongly"),
+        }
+    }
+
+    #[test]
+    fn frame_has_offset() {
+        // Packed as 'n' then a transition and its parameter, which are not used
+        let mut payload = Vec::new();
+        payload.put_u16_be(640);
+        payload.put(&b"c"[..]);
+        payload.put_u8(0);
+        payload.put(&[0x55; 640][..]);
+        match decode("grfe", &payload) {
+            Ok(Some(ServerMessage::Grfe { offset: 640, ref data }))
+                if data.len() == 640 && data[0] == 0x55 => (),
+            _ => panic!("Frame parsed wrongly"),
+        }
+    }
+
+    #[test]
+    fn text_frame_words() {
+        // Each byte for the display controller follows a byte saying whether it is a command
+        // or a character: home, 'Hi', then the start of the second line and a custom character
+        let payload = [2, 0x02, 3, b'H', 3, b'i', 2, 0xc0, 3, 0x01, 3];
+        match decode("vfdc", &payload) {
+            Ok(Some(ServerMessage::Vfdc(ref words)))
+                if words == &[0x0202, 0x0348, 0x0369, 0x02c0, 0x0301] => (),
+            _ => panic!("Text frame parsed wrongly"),
+        }
+    }
+
+    #[test]
+    fn short_display_frames_are_rejected() {
+        assert!(decode("grfs", &grfs(1, 0, 448, 0, &[])[..17]).is_err());
+        assert!(decode("grfg", &[0, 0, 1]).is_err());
+        assert!(decode("grfe", &[0, 0]).is_err());
+        assert!(decode("grfb", &[0]).is_err());
+    }
+}
@@ -0,0 +1,800 @@
+use png;
+
+use player;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// The display of the original Squeezebox, a two line vacuum fluorescent display
+const LINES: usize = 2;
+const WIDTH: usize = 40;
+// Each word of a vfdc frame is a command or a character for the display controller
+const VFD_COMMAND: u8 = 0x02;
+const VFD_CHARACTER: u8 = 0x03;
+const CLEAR: u8 = 0x01;
+const HOME: u8 = 0x02;
+const SET_ADDRESS: u8 = 0x80;
+const SECOND_LINE: u8 = 0x40;
+
+const FRAMEBUFFER: &str = "/dev/fb0";
+// The server's brightness levels run from off to this
+const MAX_BRIGHTNESS: i16 = 4;
+// Scrolling once stops at the end rather than starting over, and either stays there or goes
+// back to the background
+const SCROLL_ONCE: u16 = 1;
+const SCROLL_ONCE_AND_END: u16 = 2;
+// Otherwise text moves to the left
+const SCROLL_RIGHT: u8 = 2;
+// What the server's visu message asks for
+const VISU_METER: u8 = 1;
+const VISU_SPECTRUM: u8 = 2;
+// Braille dots are numbered down the left column then the right, with the bottom row last
+const BRAILLE_DOTS: [[u32; 4]; 2] = [[0x01, 0x02, 0x04, 0x40], [0x08, 0x10, 0x20, 0x80]];
+// The visualiser is redrawn at most this often, in milliseconds, faster shows nothing more
+const VISUALISER_REFRESH: u64 = 100;
+
+// The kind of display a model of player has
+#[derive(Clone, Copy)]
+pub enum Kind {
+    Text,
+    Graphic { width: usize, height: usize },
+}
+
+// Where the display is shown, given as TARGET[#PATH]
+#[derive(Clone, Debug)]
+pub enum Output {
+    Terminal,
+    File(PathBuf),
+    Socket(PathBuf),
+    Png(PathBuf),
+    Framebuffer(PathBuf),
+}
+
+impl Output {
+    pub fn parse(spec: Vec<&str>) -> Option<Self> {
+        match (spec.get(0).map(|s| s.to_lowercase()), spec.get(1)) {
+            (Some(ref target), None) if target == "terminal" => Some(Output::Terminal),
+            (Some(ref target), Some(path)) if target == "file" => {
+                Some(Output::File(PathBuf::from(path)))
+            }
+            (Some(ref target), Some(path)) if target == "socket" => {
+                Some(Output::Socket(PathBuf::from(path)))
+            }
+            (Some(ref target), Some(path)) if target == "png" => {
+                Some(Output::Png(PathBuf::from(path)))
+            }
+            (Some(ref target), path) if target == "fb" => Some(Output::Framebuffer(
+                PathBuf::from(path.cloned().unwrap_or(FRAMEBUFFER)),
+            )),
+            _ => None,
+        }
+    }
+
+    // Whether the output can show the given kind of display
+    pub fn shows(&self, kind: Kind) -> bool {
+        match (self, kind) {
+            (Output::Terminal, _) => true,
+            (Output::File(_), Kind::Text) | (Output::Socket(_), Kind::Text) => true,
+            (Output::Png(_), Kind::Graphic { .. }) => true,
+            (Output::Framebuffer(_), Kind::Graphic { .. }) => true,
+            _ => false,
+        }
+    }
+}
+
+// The part of the display given over to one channel of the visualiser
+#[derive(Clone)]
+pub struct VisuRegion {
+    position: usize,
+    width: usize,
+    reversed: bool,
+    bar_width: usize,
+    bar_space: usize,
+}
+
+impl VisuRegion {
+    fn contains(&self, x: usize) -> bool {
+        x >= self.position && x < self.position + self.width
+    }
+
+    fn bars(&self) -> usize {
+        self.width / (self.bar_width + self.bar_space)
+    }
+}
+
+#[derive(Clone)]
+pub enum Visualiser {
+    Meter(Vec<VisuRegion>),
+    Spectrum(Vec<VisuRegion>),
+}
+
+impl Visualiser {
+    // Meters are given as: channels, style, then position and width of each channel.
+    // Spectrums are given as: channels, bandwidth, preemphasis, then position, width,
+    // orientation, bar width, bar space and three intensities for each channel.
+    // Anything else turns the visualiser off.
+    pub fn from_params(which: u8, params: &[u32]) -> Option<Self> {
+        let regions: Vec<VisuRegion> = match which {
+            VISU_METER if params.len() > 2 => params[2..]
+                .chunks(2)
+                .filter(|channel| channel.len() == 2)
+                .map(|channel| VisuRegion {
+                    position: channel[0] as usize,
+                    width: channel[1] as usize,
+                    reversed: false,
+                    bar_width: 1,
+                    bar_space: 0,
+                })
+                .collect(),
+            VISU_SPECTRUM if params.len() > 3 => params[3..]
+                .chunks(8)
+                .filter(|channel| channel.len() == 8)
+                .map(|channel| VisuRegion {
+                    position: channel[0] as usize,
+                    width: channel[1] as usize,
+                    reversed: channel[2] != 0,
+                    bar_width: (channel[3] as usize).max(1),
+                    bar_space: channel[4] as usize,
+                })
+                .collect(),
+            _ => return None,
+        };
+
+        match which {
+            VISU_METER => Some(Visualiser::Meter(regions)),
+            _ => Some(Visualiser::Spectrum(regions)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Visualiser::Meter(_) => "meter",
+            Visualiser::Spectrum(_) => "spectrum",
+        }
+    }
+
+    pub fn wants_levels(&self) -> bool {
+        match self {
+            Visualiser::Meter(_) => true,
+            Visualiser::Spectrum(_) => false,
+        }
+    }
+
+    // Enough bands to give each bar of the widest channel its own
+    pub fn bands(&self) -> Option<u32> {
+        match self {
+            Visualiser::Meter(_) => None,
+            Visualiser::Spectrum(regions) => regions
+                .iter()
+                .map(|region| region.bars() as u32)
+                .max()
+                .map(|bands| bands.max(1)),
+        }
+    }
+}
+
+// The latest from the visualiser taps, all in dB
+#[derive(Clone, Default)]
+pub struct VisuData {
+    pub levels: Vec<f64>,
+    pub spectrum: Vec<f32>,
+}
+
+pub enum Display {
+    Text(TextDisplay),
+    Graphic(GraphicDisplay),
+}
+
+impl Display {
+    pub fn new(kind: Kind, output: Output) -> Option<Self> {
+        if !output.shows(kind) {
+            warn!("Unable to show this model's display on: {:?}", output);
+            return None;
+        }
+
+        Some(match kind {
+            Kind::Text => Display::Text(TextDisplay::new(output)),
+            Kind::Graphic { width, height } => {
+                Display::Graphic(GraphicDisplay::new(width, height, output))
+            }
+        })
+    }
+}
+
+pub struct TextDisplay {
+    lines: [[u8; WIDTH]; LINES],
+    line: usize,
+    column: usize,
+    output: Output,
+    socket: Option<UnixStream>,
+}
+
+impl TextDisplay {
+    pub fn new(output: Output) -> Self {
+        TextDisplay {
+            lines: [[b' '; WIDTH]; LINES],
+            line: 0,
+            column: 0,
+            output: output,
+            socket: None,
+        }
+    }
+
+    // Play the frame through the display controller, then show the result
+    pub fn update(&mut self, frame: &[u16]) {
+        for word in frame {
+            let (kind, value) = ((word >> 8) as u8, *word as u8);
+            match kind {
+                VFD_COMMAND if value == CLEAR => {
+                    self.lines = [[b' '; WIDTH]; LINES];
+                    self.line = 0;
+                    self.column = 0;
+                }
+                VFD_COMMAND if value == HOME => {
+                    self.line = 0;
+                    self.column = 0;
+                }
+                VFD_COMMAND if value & SET_ADDRESS != 0 => {
+                    let address = value & !SET_ADDRESS;
+                    self.line = if address >= SECOND_LINE { 1 } else { 0 };
+                    self.column = (address % SECOND_LINE) as usize;
+                }
+                VFD_CHARACTER => {
+                    if self.column < WIDTH {
+                        self.lines[self.line][self.column] = value;
+                        self.column += 1;
+                    }
+                }
+                // Brightness, custom characters and the like are of no use to us
+                _ => (),
+            }
+        }
+
+        if let Err(e) = self.show() {
+            warn!("Unable to update display: {}", e);
+            self.socket = None;
+        }
+    }
+
+    fn lines(&self) -> Vec<String> {
+        self.lines
+            .iter()
+            .map(|line| {
+                line.iter()
+                    .map(|c| match *c {
+                        0x20..=0x7e => *c as char,
+                        0xa0..=0xff => *c as char,
+                        // The server draws progress bars and symbols with custom characters
+                        0x00..=0x07 => '#',
+                        _ => ' ',
+                    })
+                    .collect::<String>()
+                    .trim_end()
+                    .to_owned()
+            })
+            .collect()
+    }
+
+    fn show(&mut self) -> io::Result<()> {
+        let text = self.lines().join("\n") + "\n";
+        match self.output {
+            Output::Terminal => write_terminal(&text),
+            Output::File(ref path) => fs::write(path, text),
+            Output::Socket(ref path) => {
+                if self.socket.is_none() {
+                    self.socket = Some(UnixStream::connect(path)?);
+                }
+                match self.socket {
+                    Some(ref mut socket) => socket.write_all(text.as_bytes()),
+                    None => Ok(()),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// How the server wants text scrolled, times are in milliseconds and sizes in pixels. The width
+// is of the scroll frame, less a screen, so is as far as the scroll goes
+pub struct ScrollSettings {
+    pub direction: u8,
+    pub pause: u32,
+    pub speed: u32,
+    pub by: u16,
+    pub mode: u16,
+    pub width: u16,
+}
+
+struct Scroll {
+    frame: Vec<u8>,
+    width: usize,
+    // How much of the display scrolls, which the server only says when starting
+    window: usize,
+    by: usize,
+    speed: u32,
+    pause_steps: u32,
+    mode: u16,
+    right: bool,
+    started: bool,
+    position: usize,
+    waiting: u32,
+}
+
+impl Scroll {
+    fn start_position(&self) -> usize {
+        if self.right {
+            self.width
+        } else {
+            0
+        }
+    }
+
+    fn end_position(&self) -> usize {
+        if self.right {
+            0
+        } else {
+            self.width
+        }
+    }
+}
+
+// A bitmap display, which the server sends as columns of pixels with the top pixel
+// in the most significant bit of the first byte
+pub struct GraphicDisplay {
+    width: usize,
+    height: usize,
+    frame: Vec<u8>,
+    brightness: i16,
+    scroll: Option<Scroll>,
+    visualiser: Option<(Visualiser, VisuData)>,
+    visualised: Instant,
+    output: Output,
+}
+
+impl GraphicDisplay {
+    pub fn new(width: usize, height: usize, output: Output) -> Self {
+        GraphicDisplay {
+            width: width,
+            height: height,
+            frame: vec![0; width * height / 8],
+            brightness: MAX_BRIGHTNESS,
+            scroll: None,
+            visualiser: None,
+            visualised: Instant::now(),
+            output: output,
+        }
+    }
+
+    // The server leaves space in its frames for us to draw the visualiser into
+    pub fn visualise(&mut self, visualiser: Option<Visualiser>, data: &VisuData) {
+        self.visualiser = visualiser.map(|visualiser| (visualiser, data.clone()));
+        self.visualised = Instant::now();
+        self.render();
+    }
+
+    // The taps report more often than the display is redrawn
+    pub fn visu_data(&mut self, data: &VisuData) {
+        if let Some((_, ref mut current)) = self.visualiser {
+            *current = data.clone();
+        } else {
+            return;
+        }
+        if self.visualised.elapsed() >= Duration::from_millis(VISUALISER_REFRESH) {
+            self.visualised = Instant::now();
+            self.render();
+        }
+    }
+
+    // A new frame replaces whatever was scrolling
+    pub fn frame(&mut self, offset: usize, data: &[u8]) {
+        self.scroll = None;
+        let end = (offset + data.len()).min(self.frame.len());
+        if offset < end {
+            self.frame[offset..end].copy_from_slice(&data[..end - offset]);
+        }
+        self.render();
+    }
+
+    pub fn brightness(&mut self, brightness: i16) {
+        self.brightness = brightness.min(MAX_BRIGHTNESS);
+        self.render();
+    }
+
+    // The text to scroll comes first and is held until the server starts it going. A long
+    // frame comes in parts, the first of which starts a new scroll
+    pub fn scroll_frame(&mut self, settings: ScrollSettings, offset: usize, data: &[u8]) {
+        if offset > 0 {
+            if let Some(ref mut scroll) = self.scroll {
+                if !scroll.started {
+                    if scroll.frame.len() < offset + data.len() {
+                        scroll.frame.resize(offset + data.len(), 0);
+                    }
+                    scroll.frame[offset..offset + data.len()].copy_from_slice(data);
+                }
+            }
+            return;
+        }
+
+        let speed = settings.speed.max(1);
+        let right = settings.direction == SCROLL_RIGHT;
+        self.scroll = Some(Scroll {
+            frame: data.to_vec(),
+            width: settings.width as usize,
+            window: self.width,
+            by: settings.by.max(1) as usize,
+            speed: speed,
+            pause_steps: settings.pause / speed,
+            mode: settings.mode,
+            right: right,
+            started: false,
+            position: if right { settings.width as usize } else { 0 },
+            waiting: 0,
+        });
+    }
+
+    // Start scrolling the given width of the display over the given background, returning how
+    // often in milliseconds to step the scroll along
+    pub fn scroll_start(&mut self, window: usize, data: &[u8]) -> Option<u32> {
+        let len = data.len().min(self.frame.len());
+        self.frame[..len].copy_from_slice(&data[..len]);
+        let width = self.width;
+        let speed = match self.scroll {
+            Some(ref mut scroll) => {
+                scroll.started = true;
+                scroll.window = window.min(width);
+                scroll.position = scroll.start_position();
+                scroll.waiting = scroll.pause_steps;
+                scroll.speed
+            }
+            None => return None,
+        };
+        self.render();
+        Some(speed)
+    }
+
+    // Move the scroll along, returning false once it has finished
+    pub fn scroll_step(&mut self) -> bool {
+        let running = match self.scroll {
+            Some(ref mut scroll) if scroll.waiting > 0 => {
+                scroll.waiting -= 1;
+                return true;
+            }
+            Some(ref mut scroll) if scroll.position == scroll.end_position() => {
+                match scroll.mode {
+                    SCROLL_ONCE => false,
+                    SCROLL_ONCE_AND_END => {
+                        scroll.started = false;
+                        false
+                    }
+                    _ => {
+                        scroll.position = scroll.start_position();
+                        scroll.waiting = scroll.pause_steps;
+                        true
+                    }
+                }
+            }
+            Some(ref mut scroll) => {
+                scroll.position = if scroll.right {
+                    scroll.position.saturating_sub(scroll.by)
+                } else {
+                    (scroll.position + scroll.by).min(scroll.width)
+                };
+                true
+            }
+            None => false,
+        };
+        self.render();
+        running
+    }
+
+    fn column_bytes(&self) -> usize {
+        self.height / 8
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> bool {
+        if let Some(lit) = self.visualiser_pixel(x, y) {
+            return lit;
+        }
+
+        let bytes = self.column_bytes();
+        // The scrolling part of the display shows the scroll frame once it has started
+        let (frame, x) = match self.scroll {
+            Some(ref scroll) if scroll.started && x < scroll.window => {
+                (&scroll.frame[..], x + scroll.position)
+            }
+            _ => (&self.frame[..], x),
+        };
+        frame
+            .get(x * bytes + y / 8)
+            .map_or(false, |byte| byte & (0x80 >> (y % 8)) != 0)
+    }
+
+    fn visualiser_pixel(&self, x: usize, y: usize) -> Option<bool> {
+        let (visualiser, data) = self.visualiser.as_ref()?;
+        let floor = player::VISUALISER_FLOOR as f64;
+        let value = match visualiser {
+            Visualiser::Meter(regions) => {
+                let channel = regions.iter().position(|region| region.contains(x))?;
+                data.levels
+                    .get(channel)
+                    .or_else(|| data.levels.last())
+                    .cloned()
+                    .unwrap_or(floor)
+            }
+            Visualiser::Spectrum(regions) => {
+                let region = regions.iter().find(|region| region.contains(x))?;
+                let step = region.bar_width + region.bar_space;
+                let offset = x - region.position;
+                if offset % step >= region.bar_width {
+                    return Some(false);
+                }
+                let bar = if region.reversed {
+                    region.bars().saturating_sub(offset / step + 1)
+                } else {
+                    offset / step
+                };
+                data.spectrum
+                    .get(bar)
+                    .map_or(floor, |magnitude| *magnitude as f64)
+            }
+        };
+
+        // Bars grow up from the bottom of the display
+        let fraction = ((value - floor) / -floor).max(0.0).min(1.0);
+        Some((self.height - y) as f64 <= fraction * self.height as f64)
+    }
+
+    // How bright a lit pixel is, from 0 to 255
+    fn level(&self) -> u8 {
+        (self.brightness.max(0) as u32 * 255 / MAX_BRIGHTNESS as u32) as u8
+    }
+
+    fn render(&self) {
+        let result = match self.output {
+            Output::Terminal => write_terminal(&self.braille()),
+            Output::Png(ref path) => self.write_png(path),
+            Output::Framebuffer(ref path) => self.write_framebuffer(path),
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            warn!("Unable to update display: {}", e);
+        }
+    }
+
+    fn braille(&self) -> String {
+        let mut text = String::new();
+        for row in (0..self.height).step_by(4) {
+            for column in (0..self.width).step_by(2) {
+                let mut dots = 0;
+                for (dx, dy) in (0..2).flat_map(|dx| (0..4).map(move |dy| (dx, dy))) {
+                    if self.level() > 0 && self.pixel(column + dx, row + dy) {
+                        dots |= BRAILLE_DOTS[dx][dy];
+                    }
+                }
+                text.push(::std::char::from_u32(0x2800 + dots).unwrap_or(' '));
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    fn levels(&self) -> Vec<u8> {
+        let level = self.level();
+        let mut levels = Vec::with_capacity(self.width * self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                levels.push(if self.pixel(x, y) { level } else { 0 });
+            }
+        }
+        levels
+    }
+
+    // Write to the side and move into place so readers never see half an image
+    fn write_png(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("png.tmp");
+        {
+            let mut encoder = png::Encoder::new(
+                BufWriter::new(File::create(&tmp)?),
+                self.width as u32,
+                self.height as u32,
+            );
+            encoder.set_color(png::ColorType::Grayscale);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder
+                .write_header()
+                .and_then(|mut writer| writer.write_image_data(&self.levels()))
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        }
+        fs::rename(tmp, path)
+    }
+
+    // Draw in the top left corner, scaled up as far as the screen allows
+    fn write_framebuffer(&self, path: &Path) -> io::Result<()> {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let info = Path::new("/sys/class/graphics").join(name);
+        let read = |attr: &str| -> io::Result<String> {
+            Ok(fs::read_to_string(info.join(attr))?.trim().to_owned())
+        };
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Unknown framebuffer format");
+
+        let bits_per_pixel: usize = read("bits_per_pixel")?.parse().map_err(|_| invalid())?;
+        let stride: usize = read("stride")?.parse().map_err(|_| invalid())?;
+        let size: Vec<usize> = read("virtual_size")?
+            .split(',')
+            .filter_map(|n| n.parse().ok())
+            .collect();
+        let (screen_width, screen_height) = match size.as_slice() {
+            [width, height] => (*width, *height),
+            _ => return Err(invalid()),
+        };
+        let scale = (screen_width / self.width)
+            .min(screen_height / self.height)
+            .max(1);
+
+        let levels = self.levels();
+        let mut fb = OpenOptions::new().write(true).open(path)?;
+        for y in 0..(self.height * scale).min(screen_height) {
+            let mut line = Vec::with_capacity(stride);
+            for x in 0..(self.width * scale).min(screen_width) {
+                let level = levels[(y / scale) * self.width + x / scale];
+                match bits_per_pixel {
+                    16 => {
+                        let rgb = ((level as u16 >> 3) << 11)
+                            | ((level as u16 >> 2) << 5)
+                            | (level as u16 >> 3);
+                        line.extend_from_slice(&rgb.to_le_bytes());
+                    }
+                    24 => line.extend_from_slice(&[level, level, level]),
+                    32 => line.extend_from_slice(&[level, level, level, 0xff]),
+                    _ => return Err(invalid()),
+                }
+            }
+            fb.seek(SeekFrom::Start((y * stride) as u64))?;
+            fb.write_all(&line)?;
+        }
+        Ok(())
+    }
+}
+
+// Clear the screen and go home before drawing
+fn write_terminal(text: &str) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    write!(stdout, "\x1b[2J\x1b[H{}", text)?;
+    stdout.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Four columns of eight pixels, one byte a column
+    fn display() -> GraphicDisplay {
+        GraphicDisplay::new(4, 8, Output::File(PathBuf::from("/dev/null")))
+    }
+
+    fn settings(direction: u8, mode: u16) -> ScrollSettings {
+        ScrollSettings {
+            direction: direction,
+            pause: 0,
+            speed: 50,
+            by: 2,
+            mode: mode,
+            width: 4,
+        }
+    }
+
+    fn lit(display: &GraphicDisplay) -> Vec<bool> {
+        (0..4).map(|x| display.pixel(x, 0)).collect()
+    }
+
+    // Eight columns, sent in two parts, with the first and last marked
+    fn scroll(display: &mut GraphicDisplay, direction: u8, mode: u16) {
+        display.scroll_frame(settings(direction, mode), 0, &[0x80, 0, 0, 0]);
+        display.scroll_frame(settings(direction, mode), 4, &[0, 0, 0, 0x80]);
+    }
+
+    // Words for the display controller as a vfdc frame has them
+    fn text(text: &str) -> Vec<u16> {
+        text.bytes()
+            .map(|c| (VFD_CHARACTER as u16) << 8 | c as u16)
+            .collect()
+    }
+
+    fn command(command: u8) -> u16 {
+        (VFD_COMMAND as u16) << 8 | command as u16
+    }
+
+    #[test]
+    fn text_is_put_on_both_lines() {
+        let mut display = TextDisplay::new(Output::File(PathBuf::from("/dev/null")));
+        let mut frame = vec![command(CLEAR)];
+        frame.extend(text("Now Playing (1 of 12)"));
+        frame.push(command(SET_ADDRESS | SECOND_LINE));
+        frame.extend(text("Blue in Green"));
+        // A progress bar drawn with a custom character
+        frame.push((VFD_CHARACTER as u16) << 8 | 0x01);
+        display.update(&frame);
+        assert_eq!(
+            display.lines(),
+            vec!["Now Playing (1 of 12)", "Blue in Green#"]
+        );
+
+        // Going home writes over the start of the first line, leaving the rest
+        let mut frame = vec![command(HOME)];
+        frame.extend(text("Paused"));
+        display.update(&frame);
+        assert_eq!(
+            display.lines(),
+            vec!["Pausedaying (1 of 12)", "Blue in Green#"]
+        );
+    }
+
+    #[test]
+    fn text_stops_at_the_end_of_the_line() {
+        let mut display = TextDisplay::new(Output::File(PathBuf::from("/dev/null")));
+        let mut frame = vec![command(SET_ADDRESS | 36)];
+        frame.extend(text("123456"));
+        display.update(&frame);
+        assert_eq!(display.lines(), vec![format!("{:36}1234", ""), String::new()]);
+    }
+
+    #[test]
+    fn scroll_parts_are_put_together() {
+        let mut display = display();
+        scroll(&mut display, 1, SCROLL_ONCE);
+        assert_eq!(display.scroll_start(4, &[0; 4]), Some(50));
+        assert_eq!(lit(&display), vec![true, false, false, false]);
+
+        assert!(display.scroll_step());
+        assert_eq!(lit(&display), vec![false, false, false, false]);
+        assert!(display.scroll_step());
+        assert_eq!(lit(&display), vec![false, false, false, true]);
+
+        // Once and stop leaves the end showing
+        assert!(!display.scroll_step());
+        assert_eq!(lit(&display), vec![false, false, false, true]);
+    }
+
+    #[test]
+    fn scroll_to_the_right() {
+        let mut display = display();
+        scroll(&mut display, SCROLL_RIGHT, SCROLL_ONCE_AND_END);
+        display.scroll_start(4, &[0, 0x80, 0, 0]);
+        assert_eq!(lit(&display), vec![false, false, false, true]);
+
+        assert!(display.scroll_step());
+        assert!(display.scroll_step());
+        assert_eq!(lit(&display), vec![true, false, false, false]);
+
+        // Once and end goes back to the background
+        assert!(!display.scroll_step());
+        assert_eq!(lit(&display), vec![false, true, false, false]);
+    }
+
+    #[test]
+    fn continuous_scroll_starts_over() {
+        let mut display = display();
+        scroll(&mut display, 1, 0);
+        display.scroll_start(4, &[0; 4]);
+        for _ in 0..2 {
+            assert!(display.scroll_step());
+        }
+        assert!(display.scroll_step());
+        assert_eq!(lit(&display), vec![true, false, false, false]);
+    }
+
+    #[test]
+    fn only_the_window_scrolls() {
+        let mut display = display();
+        scroll(&mut display, 1, 0);
+        display.scroll_start(2, &[0, 0, 0x80, 0x80]);
+        assert_eq!(lit(&display), vec![true, false, true, true]);
+    }
+}
@@ -0,0 +1,211 @@
+// A C interface to the player, see include/storm.h
+
+use futures::Stream;
+
+use api;
+use hooks;
+use player;
+use proto;
+use Builder;
+use Handle;
+
+use std::ffi::{CStr, CString};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+use std::ptr;
+use std::str::FromStr;
+use std::thread;
+
+pub const STORM_STATE_STOP: c_int = 0;
+pub const STORM_STATE_PLAY: c_int = 1;
+pub const STORM_STATE_PAUSE: c_int = 2;
+
+/// Called from a thread of the player's for every event, with details such as the
+/// volume, the error or the track as "artist - title - album". Neither string is valid
+/// after the callback returns.
+pub type StormEventCallback =
+    Option<extern "C" fn(event: *const c_char, detail: *const c_char, user_data: *mut c_void)>;
+
+/// An opaque handle to a running player.
+pub struct StormPlayer {
+    handle: Handle,
+    callbacks: Option<thread::JoinHandle<()>>,
+}
+
+#[repr(C)]
+pub struct StormStatus {
+    pub state: c_int,
+    pub power: c_int,
+    pub volume: c_uint,
+    pub elapsed_milliseconds: c_uint,
+    pub buffer_fullness: c_uint,
+    pub output_buffer_fullness: c_uint,
+}
+
+// The application promises the user data can be used from the player's threads
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+
+unsafe fn to_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        None
+    } else {
+        CStr::from_ptr(s).to_str().ok()
+    }
+}
+
+fn detail(event: &hooks::Event) -> String {
+    match event {
+        hooks::Event::Error(error) => error.clone(),
+        hooks::Event::Volume(volume) => volume.to_string(),
+        hooks::Event::Track(track) => [&track.artist, &track.title, &track.album]
+            .iter()
+            .map(|field| field.as_ref().map_or("", |s| s.as_str()))
+            .collect::<Vec<&str>>()
+            .join(" - "),
+        _ => String::new(),
+    }
+}
+
+/// Start a player, returning NULL if it could not be started.
+///
+/// The server is an IPv4 address, with a port if it is not the usual one, or NULL to look
+/// for one. The output is given as for the --output option, e.g. "alsa#hw:0,0", or NULL for
+/// the default.
+///
+/// The callback is not called again once storm_stop has returned.
+#[no_mangle]
+pub unsafe extern "C" fn storm_start(
+    server: *const c_char,
+    name: *const c_char,
+    output: *const c_char,
+    callback: StormEventCallback,
+    user_data: *mut c_void,
+) -> *mut StormPlayer {
+    let mut builder = Builder::new();
+
+    if let Some(server) = to_str(server) {
+        let addr = SocketAddrV4::from_str(server).or_else(|_| {
+            Ipv4Addr::from_str(server).map(|ip| SocketAddrV4::new(ip, proto::SLIMPROTO_PORT))
+        });
+        match addr {
+            Ok(addr) => builder = builder.server(*addr.ip()).server_port(addr.port()),
+            Err(_) => {
+                error!("Unable to parse server address: {}", server);
+                return ptr::null_mut();
+            }
+        }
+    }
+    if let Some(name) = to_str(name) {
+        builder = builder.name(name);
+    }
+    if let Some(output) = to_str(output) {
+        builder = builder.output(player::AudioDevice::from(
+            output.split('#').collect::<Vec<&str>>(),
+        ));
+    }
+
+    // Events run out once the player has stopped and let go of every sender
+    let callbacks = callback.map(|callback| {
+        let (sender, events) = ::futures::sync::mpsc::unbounded();
+        builder = builder.events(sender);
+        let user_data = UserData(user_data);
+        thread::spawn(move || {
+            let user_data = user_data;
+            for event in events.wait().filter_map(|event| event.ok()) {
+                let name = CString::new(event.name()).unwrap_or_default();
+                let detail = CString::new(detail(&event)).unwrap_or_default();
+                callback(name.as_ptr(), detail.as_ptr(), user_data.0);
+            }
+        })
+    });
+
+    match builder.spawn() {
+        Ok(handle) => Box::into_raw(Box::new(StormPlayer {
+            handle: handle,
+            callbacks: callbacks,
+        })),
+        Err(e) => {
+            error!("Unable to start player: {}", e);
+            if let Some(callbacks) = callbacks {
+                let _ = callbacks.join();
+            }
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Stop the player and free it, the handle must not be used again. Once this returns the
+/// callback has finished with the user data.
+#[no_mangle]
+pub unsafe extern "C" fn storm_stop(player: *mut StormPlayer) {
+    if !player.is_null() {
+        let player = *Box::from_raw(player);
+        if let Err(e) = player.handle.stop() {
+            warn!("Player stopped with: {}", e);
+        }
+        if let Some(callbacks) = player.callbacks {
+            let _ = callbacks.join();
+        }
+    }
+}
+
+unsafe fn command(player: *mut StormPlayer, command: api::Command) -> c_int {
+    match player.as_ref() {
+        Some(player) if player.handle.command(command) => 0,
+        _ => -1,
+    }
+}
+
+/// Set the volume from 0 to 100, returning 0 on success.
+#[no_mangle]
+pub unsafe extern "C" fn storm_set_volume(player: *mut StormPlayer, volume: c_uint) -> c_int {
+    command(player, api::Command::Volume(volume.min(100) as u8))
+}
+
+/// Returns 0 on success.
+#[no_mangle]
+pub unsafe extern "C" fn storm_pause(player: *mut StormPlayer) -> c_int {
+    command(player, api::Command::Pause)
+}
+
+/// Returns 0 on success.
+#[no_mangle]
+pub unsafe extern "C" fn storm_resume(player: *mut StormPlayer) -> c_int {
+    command(player, api::Command::Resume)
+}
+
+/// Switch the player on if power is non-zero, otherwise off, returning 0 on success.
+#[no_mangle]
+pub unsafe extern "C" fn storm_power(player: *mut StormPlayer, power: c_int) -> c_int {
+    command(player, api::Command::Power(power != 0))
+}
+
+/// Fill in the status, returning 0 on success.
+#[no_mangle]
+pub unsafe extern "C" fn storm_status(player: *mut StormPlayer, status: *mut StormStatus) -> c_int {
+    let (player, status) = match (player.as_ref(), status.as_mut()) {
+        (Some(player), Some(status)) => (player, status),
+        _ => return -1,
+    };
+
+    match player.handle.status() {
+        Some(current) => {
+            *status = StormStatus {
+                state: match current.state {
+                    "play" => STORM_STATE_PLAY,
+                    "pause" => STORM_STATE_PAUSE,
+                    _ => STORM_STATE_STOP,
+                },
+                power: current.power as c_int,
+                volume: current.volume as c_uint,
+                elapsed_milliseconds: current.elapsed_milliseconds,
+                buffer_fullness: current.buffer_fullness,
+                output_buffer_fullness: current.output_buffer_fullness,
+            };
+            0
+        }
+        None => -1,
+    }
+}
@@ -0,0 +1,65 @@
+use player;
+
+use std::process::Command;
+use std::thread;
+
+#[derive(Clone, Debug)]
+pub enum Event {
+    PowerOn,
+    PowerOff,
+    Play,
+    Pause,
+    Resume,
+    Stop,
+    Error(String),
+    Volume(u8),
+    Track(player::TrackInfo),
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Event::PowerOn => "power_on",
+            Event::PowerOff => "power_off",
+            Event::Play => "play",
+            Event::Pause => "pause",
+            Event::Resume => "resume",
+            Event::Stop => "stop",
+            Event::Error(_) => "error",
+            Event::Volume(_) => "volume",
+            Event::Track(_) => "track",
+        }
+    }
+}
+
+// Run the user's command for the event without waiting for it to finish
+pub fn run(command: &str, event: Event, player_name: &str, track: &player::TrackInfo) {
+    info!("Running hook for event: {}", event.name());
+
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(command)
+        .env("STORM_EVENT", event.name())
+        .env("STORM_PLAYER_NAME", player_name)
+        .env("STORM_TRACK_TITLE", track.title.as_ref().map_or("", |s| s.as_str()))
+        .env("STORM_TRACK_ARTIST", track.artist.as_ref().map_or("", |s| s.as_str()))
+        .env("STORM_TRACK_ALBUM", track.album.as_ref().map_or("", |s| s.as_str()));
+
+    if let Event::Error(ref error) = event {
+        cmd.env("STORM_ERROR", error);
+    }
+
+    match cmd.spawn() {
+        // Reap the child so it does not hang around as a zombie
+        Ok(mut child) => {
+            thread::spawn(move || {
+                if let Ok(status) = child.wait() {
+                    if !status.success() {
+                        warn!("Event hook exited with: {}", status);
+                    }
+                }
+            });
+        }
+        Err(e) => error!("Unable to run event hook: {}", e),
+    }
+}
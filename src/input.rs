@@ -0,0 +1,518 @@
+use actix;
+use actix::{Actor, AsyncContext, Context};
+use libc;
+
+use attach;
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::mem;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::slice;
+use std::thread;
+
+const LIRC_SOCKET: &str = "/var/run/lirc/lircd";
+// From linux/input-event-codes.h
+const EV_KEY: u16 = 0x01;
+const KEY_RELEASE: i32 = 0;
+
+// Codes of the buttons on the Squeezebox remote, as the server knows them
+const BUTTONS: &[(&str, u32)] = &[
+    ("0", 0x76899867),
+    ("1", 0x7689f00f),
+    ("2", 0x768908f7),
+    ("3", 0x76898877),
+    ("4", 0x768948b7),
+    ("5", 0x7689c837),
+    ("6", 0x768928d7),
+    ("7", 0x7689a857),
+    ("8", 0x76896897),
+    ("9", 0x7689e817),
+    ("arrow_up", 0x7689e01f),
+    ("arrow_down", 0x7689b04f),
+    ("arrow_left", 0x7689906f),
+    ("arrow_right", 0x7689d02f),
+    ("volup", 0x7689807f),
+    ("voldown", 0x768900ff),
+    ("power", 0x768940bf),
+    ("rew", 0x7689c03f),
+    ("fwd", 0x7689a05f),
+    ("pause", 0x768920df),
+    ("play", 0x768910ef),
+    ("add", 0x7689609f),
+    ("search", 0x768958a7),
+    ("shuffle", 0x7689d827),
+    ("repeat", 0x768938c7),
+    ("sleep", 0x7689b847),
+    ("now_playing", 0x76897887),
+    ("size", 0x7689f807),
+    ("brightness", 0x768904fb),
+    ("favorites", 0x768918e7),
+    ("browse", 0x7689708f),
+    ("muting", 0x7689c43b),
+    ("home", 0x768922dd),
+];
+
+// Names of the keys we map by default, evdev gives us the numbers
+const KEYS: &[(u16, &str)] = &[
+    (2, "KEY_1"),
+    (3, "KEY_2"),
+    (4, "KEY_3"),
+    (5, "KEY_4"),
+    (6, "KEY_5"),
+    (7, "KEY_6"),
+    (8, "KEY_7"),
+    (9, "KEY_8"),
+    (10, "KEY_9"),
+    (11, "KEY_0"),
+    (28, "KEY_ENTER"),
+    (102, "KEY_HOME"),
+    (103, "KEY_UP"),
+    (105, "KEY_LEFT"),
+    (106, "KEY_RIGHT"),
+    (108, "KEY_DOWN"),
+    (113, "KEY_MUTE"),
+    (114, "KEY_VOLUMEDOWN"),
+    (115, "KEY_VOLUMEUP"),
+    (116, "KEY_POWER"),
+    (119, "KEY_PAUSE"),
+    (142, "KEY_SLEEP"),
+    (163, "KEY_NEXTSONG"),
+    (164, "KEY_PLAYPAUSE"),
+    (165, "KEY_PREVIOUSSONG"),
+    (168, "KEY_REWIND"),
+    (207, "KEY_PLAY"),
+    (208, "KEY_FASTFORWARD"),
+    (217, "KEY_SEARCH"),
+    (352, "KEY_OK"),
+    (364, "KEY_FAVORITES"),
+    (410, "KEY_SHUFFLE"),
+];
+
+const DEFAULT_KEYMAP: &[(&str, &str)] = &[
+    ("KEY_0", "0"),
+    ("KEY_1", "1"),
+    ("KEY_2", "2"),
+    ("KEY_3", "3"),
+    ("KEY_4", "4"),
+    ("KEY_5", "5"),
+    ("KEY_6", "6"),
+    ("KEY_7", "7"),
+    ("KEY_8", "8"),
+    ("KEY_9", "9"),
+    ("KEY_UP", "arrow_up"),
+    ("KEY_DOWN", "arrow_down"),
+    ("KEY_LEFT", "arrow_left"),
+    ("KEY_RIGHT", "arrow_right"),
+    ("KEY_ENTER", "arrow_right"),
+    ("KEY_OK", "arrow_right"),
+    ("KEY_VOLUMEUP", "volup"),
+    ("KEY_VOLUMEDOWN", "voldown"),
+    ("KEY_MUTE", "muting"),
+    ("KEY_POWER", "power"),
+    ("KEY_PLAY", "play"),
+    ("KEY_PAUSE", "pause"),
+    ("KEY_PLAYPAUSE", "pause"),
+    ("KEY_NEXTSONG", "fwd"),
+    ("KEY_FASTFORWARD", "fwd"),
+    ("KEY_PREVIOUSSONG", "rew"),
+    ("KEY_REWIND", "rew"),
+    ("KEY_HOME", "home"),
+    ("KEY_SEARCH", "search"),
+    ("KEY_SHUFFLE", "shuffle"),
+    ("KEY_SLEEP", "sleep"),
+    ("KEY_FAVORITES", "favorites"),
+];
+
+// Where key presses come from, given as SOURCE[#PATH]
+#[derive(Clone, Debug)]
+pub enum Device {
+    Evdev(PathBuf),
+    Lirc(PathBuf),
+}
+
+impl Device {
+    pub fn parse(spec: Vec<&str>) -> Option<Self> {
+        match (spec.get(0).map(|s| s.to_lowercase()), spec.get(1)) {
+            (Some(ref source), Some(path)) if source == "evdev" => {
+                Some(Device::Evdev(PathBuf::from(path)))
+            }
+            (Some(ref source), path) if source == "lirc" => Some(Device::Lirc(PathBuf::from(
+                path.cloned().unwrap_or(LIRC_SOCKET),
+            ))),
+            _ => None,
+        }
+    }
+
+    fn open(&self) -> io::Result<Box<dyn EventSource>> {
+        match self {
+            Device::Evdev(path) => Ok(Box::new(Evdev(File::open(path)?))),
+            Device::Lirc(path) => Ok(Box::new(Lirc(BufReader::new(UnixStream::connect(
+                path,
+            )?)))),
+        }
+    }
+}
+
+// Anything that gives us the names of keys as they are pressed, None when there are no more
+pub trait EventSource: Send {
+    fn next_key(&mut self) -> io::Result<Option<String>>;
+}
+
+struct Evdev<R>(R);
+
+impl<R: Read + Send> EventSource for Evdev<R> {
+    fn next_key(&mut self) -> io::Result<Option<String>> {
+        // Events are read whole, as the kernel writes them, whatever size the time is here
+        let mut event: libc::input_event = unsafe { mem::zeroed() };
+
+        loop {
+            let read = {
+                let bytes = unsafe {
+                    slice::from_raw_parts_mut(
+                        &mut event as *mut libc::input_event as *mut u8,
+                        mem::size_of::<libc::input_event>(),
+                    )
+                };
+                self.0.read_exact(bytes)
+            };
+            match read {
+                Ok(_) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+
+            // Auto-repeats are passed on just as a held down remote button would be
+            if event.type_ == EV_KEY && event.value != KEY_RELEASE {
+                return Ok(Some(match KEYS.iter().find(|(c, _)| *c == event.code) {
+                    Some((_, name)) => (*name).to_owned(),
+                    None => format!("KEY_{}", event.code),
+                }));
+            }
+        }
+    }
+}
+
+// lircd sends lines like: 0000000000f40bf0 00 KEY_UP remote
+struct Lirc<R>(R);
+
+impl<R: BufRead + Send> EventSource for Lirc<R> {
+    fn next_key(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.0.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            if let Some(key) = line.split_whitespace().nth(2) {
+                return Ok(Some(key.to_owned()));
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Keymap(HashMap<String, u32>);
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap(
+            DEFAULT_KEYMAP
+                .iter()
+                .filter_map(|(key, button)| {
+                    button_code(button).map(|code| ((*key).to_owned(), code))
+                })
+                .collect(),
+        )
+    }
+}
+
+impl Keymap {
+    // Lines of KEY = BUTTON, where BUTTON is a Squeezebox button name or an IR code in hex
+    pub fn load(path: &str) -> io::Result<Self> {
+        let mut keymap = Keymap::default();
+        for (n, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.split('#').nth(0).unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut fields = line.splitn(2, '=').map(|field| field.trim());
+            match (fields.next(), fields.next().and_then(button_code)) {
+                (Some(key), Some(code)) if !key.is_empty() => {
+                    keymap.0.insert(key.to_owned(), code);
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Unable to parse line {} of keymap", n + 1),
+                    ))
+                }
+            }
+        }
+        Ok(keymap)
+    }
+
+    pub fn code(&self, key: &str) -> Option<u32> {
+        self.0.get(key).cloned()
+    }
+}
+
+fn button_code(button: &str) -> Option<u32> {
+    if button.starts_with("0x") {
+        u32::from_str_radix(&button[2..], 16).ok()
+    } else {
+        BUTTONS
+            .iter()
+            .find(|(name, _)| *name == button)
+            .map(|(_, code)| *code)
+    }
+}
+
+// The IR code of a button press, to be sent to the server
+pub struct Button(pub u32);
+
+impl actix::Message for Button {
+    type Result = ();
+}
+
+pub struct Start {
+    pub device: Device,
+    pub keymap: Keymap,
+}
+
+impl actix::Message for Start {
+    type Result = ();
+}
+
+#[derive(Default)]
+pub struct Input {
+    proto: attach::Connection,
+}
+
+impl Actor for Input {
+    type Context = Context<Self>;
+}
+
+impl actix::Supervised for Input {}
+
+impl actix::SystemService for Input {}
+
+impl actix::Handler<Start> for Input {
+    type Result = ();
+
+    fn handle(&mut self, msg: Start, ctx: &mut Context<Self>) {
+        match msg.device.open() {
+            Ok(source) => {
+                info!("Reading key presses from: {:?}", msg.device);
+                spawn(source, msg.keymap, ctx.address().recipient());
+            }
+            Err(e) => error!("Unable to open input device {:?}: {}", msg.device, e),
+        }
+    }
+}
+
+impl actix::Handler<attach::Attach> for Input {
+    type Result = ();
+
+    fn handle(&mut self, msg: attach::Attach, _ctx: &mut Context<Self>) {
+        self.proto.attach(msg);
+    }
+}
+
+impl actix::Handler<Button> for Input {
+    type Result = ();
+
+    fn handle(&mut self, msg: Button, _ctx: &mut Context<Self>) {
+        self.proto.forward(msg);
+    }
+}
+
+// Read keys from any source until it runs dry, so sources other than devices can be used
+pub fn spawn(
+    mut source: Box<dyn EventSource>,
+    keymap: Keymap,
+    recipient: actix::Recipient<Button>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || loop {
+        match source.next_key() {
+            Ok(Some(key)) => match keymap.code(&key) {
+                Some(code) => {
+                    debug!("Key {} pressed, sending: {:#010x}", key, code);
+                    let _ = recipient.do_send(Button(code));
+                }
+                None => debug!("Key {} is not mapped", key),
+            },
+            Ok(None) => {
+                info!("No more key presses to read");
+                break;
+            }
+            Err(e) => {
+                error!("Unable to read key presses: {}", e);
+                break;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix::System;
+
+    use std::env;
+    use std::io::Cursor;
+    use std::process;
+    use std::sync::{Arc, Mutex};
+
+    fn key_event(event_type: u16, code: u16, value: i32) -> Vec<u8> {
+        let mut event: libc::input_event = unsafe { mem::zeroed() };
+        event.type_ = event_type;
+        event.code = code;
+        event.value = value;
+        unsafe {
+            slice::from_raw_parts(
+                &event as *const libc::input_event as *const u8,
+                mem::size_of::<libc::input_event>(),
+            )
+        }
+        .to_vec()
+    }
+
+    fn keymap_file(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("storm-{}-{}.keymap", process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn buttons_by_name_or_code() {
+        assert_eq!(button_code("play"), Some(0x768910ef));
+        assert_eq!(button_code("arrow_up"), Some(0x7689e01f));
+        assert_eq!(button_code("0x12345678"), Some(0x12345678));
+        assert_eq!(button_code("0xnothex"), None);
+        assert_eq!(button_code("0x123456789"), None);
+        assert_eq!(button_code("Play"), None);
+        assert_eq!(button_code("eject"), None);
+    }
+
+    #[test]
+    fn keymap_adds_to_the_defaults() {
+        let path = keymap_file(
+            "good",
+            "# Remote in the kitchen\n\
+             KEY_UP = volup\n\
+             \n\
+             KEY_RED=0x7689a25d  # Not a Squeezebox button\n\
+             BTN_LEFT = pause\n",
+        );
+        let keymap = Keymap::load(path.to_str().unwrap());
+        let _ = fs::remove_file(&path);
+        let keymap = keymap.unwrap();
+
+        assert_eq!(keymap.code("KEY_UP"), button_code("volup"));
+        assert_eq!(keymap.code("KEY_RED"), Some(0x7689a25d));
+        assert_eq!(keymap.code("BTN_LEFT"), button_code("pause"));
+        assert_eq!(keymap.code("KEY_DOWN"), button_code("arrow_down"));
+        assert_eq!(keymap.code("KEY_UNKNOWN"), None);
+    }
+
+    #[test]
+    fn keymap_errors_say_where() {
+        for (name, contents) in &[
+            ("button", "KEY_UP = volup\nKEY_DOWN = eject\n"),
+            ("key", "KEY_UP = volup\n = play\n"),
+            ("equals", "KEY_UP = volup\nKEY_DOWN volup\n"),
+        ] {
+            let path = keymap_file(name, contents);
+            let keymap = Keymap::load(path.to_str().unwrap());
+            let _ = fs::remove_file(&path);
+            match keymap {
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                    assert!(e.to_string().contains("line 2"), "{}", e)
+                }
+                _ => panic!("Loaded a bad keymap: {}", name),
+            }
+        }
+
+        assert!(Keymap::load("/nonexistent/storm.keymap").is_err());
+    }
+
+    #[test]
+    fn evdev_key_presses() {
+        let mut events = Vec::new();
+        // Sync events come between key events and are not presses
+        events.extend(key_event(0, 0, 0));
+        events.extend(key_event(EV_KEY, 103, 1));
+        events.extend(key_event(EV_KEY, 103, KEY_RELEASE));
+        events.extend(key_event(EV_KEY, 999, 2));
+        // A part of an event is not one
+        events.extend(&key_event(EV_KEY, 115, 1)[..4]);
+
+        let mut source = Evdev(Cursor::new(events));
+        assert_eq!(source.next_key().unwrap(), Some("KEY_UP".to_owned()));
+        assert_eq!(source.next_key().unwrap(), Some("KEY_999".to_owned()));
+        assert_eq!(source.next_key().unwrap(), None);
+    }
+
+    #[test]
+    fn lirc_key_presses() {
+        let mut source = Lirc(Cursor::new(
+            "0000000000f40bf0 00 KEY_UP remote\n\ngarbage\n0000000000f40bf1 01 KEY_UP remote\n",
+        ));
+        assert_eq!(source.next_key().unwrap(), Some("KEY_UP".to_owned()));
+        assert_eq!(source.next_key().unwrap(), Some("KEY_UP".to_owned()));
+        assert_eq!(source.next_key().unwrap(), None);
+    }
+
+    struct Pressed {
+        buttons: Arc<Mutex<Vec<u32>>>,
+        expected: usize,
+    }
+
+    impl Actor for Pressed {
+        type Context = Context<Self>;
+    }
+
+    impl actix::Handler<Button> for Pressed {
+        type Result = ();
+
+        fn handle(&mut self, msg: Button, _ctx: &mut Context<Self>) {
+            let mut buttons = self.buttons.lock().unwrap();
+            buttons.push(msg.0);
+            if buttons.len() == self.expected {
+                System::current().stop();
+            }
+        }
+    }
+
+    #[test]
+    fn mapped_keys_are_sent_as_buttons() {
+        let mut events = Vec::new();
+        events.extend(key_event(EV_KEY, 164, 1));
+        events.extend(key_event(EV_KEY, 999, 1));
+        events.extend(key_event(EV_KEY, 115, 1));
+
+        let buttons = Arc::new(Mutex::new(Vec::new()));
+        let sys = System::new("test");
+        let pressed = Pressed {
+            buttons: buttons.clone(),
+            expected: 2,
+        }
+        .start();
+        let reader = spawn(
+            Box::new(Evdev(Cursor::new(events))),
+            Keymap::default(),
+            pressed.recipient(),
+        );
+        sys.run().unwrap();
+        reader.join().unwrap();
+
+        assert_eq!(
+            *buttons.lock().unwrap(),
+            vec![button_code("pause").unwrap(), button_code("volup").unwrap()]
+        );
+    }
+}
@@ -0,0 +1,336 @@
+//! A headless client for Logitech Media Server, for use on its own or inside other applications.
+//!
+//! ```no_run
+//! extern crate futures;
+//! extern crate storm;
+//!
+//! use futures::Stream;
+//! use std::thread;
+//!
+//! let (sender, events) = futures::sync::mpsc::unbounded();
+//! thread::spawn(move || {
+//!     for event in events.wait() {
+//!         println!("{:?}", event);
+//!     }
+//! });
+//!
+//! storm::Builder::new()
+//!     .name("Kiosk")
+//!     .buffer_size(1024)
+//!     .events(sender)
+//!     .run()
+//!     .unwrap();
+//! ```
+
+#[cfg(feature = "mpris")]
+extern crate dbus;
+#[macro_use]
+extern crate log;
+extern crate actix;
+extern crate alsa;
+extern crate bytes;
+extern crate futures;
+extern crate get_if_addrs;
+extern crate glib;
+extern crate gstreamer as gst;
+extern crate libc;
+extern crate mac_address;
+extern crate png;
+extern crate rumqtt;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate thread_control;
+extern crate tokio_codec;
+extern crate tokio_core;
+extern crate tokio_io;
+extern crate tokio_signal;
+extern crate tokio_tcp;
+extern crate tokio_timer;
+
+mod api;
+mod attach;
+mod backend;
+pub mod codec;
+mod display;
+pub mod ffi;
+mod hooks;
+mod input;
+#[cfg(feature = "mpris")]
+mod mpris;
+mod mqtt;
+mod player;
+mod proto;
+#[cfg(test)]
+mod testing;
+
+pub use api::{Command, Status};
+pub use backend::{Backend, Gstreamer, Recorder};
+pub use codec::{ClientMessage, ServerMessage, SlimCodec, StatData};
+pub use display::Output as DisplayOutput;
+pub use hooks::Event;
+pub use input::{Device as InputDevice, Keymap};
+pub use player::{
+    list_outputs, AudioDevice, DeviceRelease, OutputInfo, PlayerControl, PlayerError,
+    PlayerMessages, TrackInfo,
+};
+pub use proto::{discover, profile, Profile, PROFILES};
+
+use actix::SystemService;
+use futures::sync::mpsc;
+use futures::Future;
+
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::sync::{self, Arc};
+use std::thread;
+
+/// Where the player sends its events.
+pub type EventSender = mpsc::UnboundedSender<Event>;
+
+/// The events of a player, as a stream.
+pub type Events = mpsc::UnboundedReceiver<Event>;
+
+/// Sets up a player and runs it.
+pub struct Builder {
+    server: Option<Ipv4Addr>,
+    server_port: u16,
+    name: String,
+    options: proto::Options,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Builder {
+            server: None,
+            server_port: proto::SLIMPROTO_PORT,
+            name: String::from("Storm"),
+            options: proto::Options::default(),
+        }
+    }
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Builder::default()
+    }
+
+    /// The server to connect to, otherwise one is looked for on the local network.
+    pub fn server(mut self, server: Ipv4Addr) -> Self {
+        self.server = Some(server);
+        self
+    }
+
+    /// The port the server listens for players on, when it is not the usual one.
+    pub fn server_port(mut self, port: u16) -> Self {
+        self.server_port = port;
+        self
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_owned();
+        self
+    }
+
+    pub fn output(mut self, output: AudioDevice) -> Self {
+        self.options.output_device = output;
+        self
+    }
+
+    /// Size of the input buffer in KiB.
+    pub fn buffer_size(mut self, size: u32) -> Self {
+        self.options.bufsize = Some(size);
+        self
+    }
+
+    /// Codecs to offer the server, using the server's format codes, otherwise those
+    /// with a decoder available are offered.
+    pub fn codecs(mut self, codecs: Vec<String>) -> Self {
+        self.options.codecs = Some(codecs);
+        self
+    }
+
+    /// Extra capabilities as KEY=VALUE, which override any worked out by the player.
+    pub fn capabilities(mut self, capabilities: Vec<String>) -> Self {
+        self.options.extra_caps = capabilities;
+        self
+    }
+
+    pub fn profile(mut self, profile: &'static Profile) -> Self {
+        self.options.profile = profile;
+        self
+    }
+
+    pub fn device_release(mut self, device_release: DeviceRelease) -> Self {
+        self.options.device_release = device_release;
+        self
+    }
+
+    /// A shell command to run when the player changes state.
+    pub fn event_hook(mut self, command: &str) -> Self {
+        self.options.event_hook = Some(command.to_owned());
+        self
+    }
+
+    /// Serve the control and status API on this port of localhost.
+    pub fn api_port(mut self, port: u16) -> Self {
+        self.options.api_port = Some(port);
+        self
+    }
+
+    /// The port of the server's command line interface, which MPRIS requests are sent to.
+    /// 9090 unless the server has been set up otherwise.
+    pub fn cli_port(mut self, port: u16) -> Self {
+        self.options.cli_port = port;
+        self
+    }
+
+    /// Offer an MPRIS interface on the D-Bus session bus, which needs the "mpris" feature.
+    /// Starting the player fails if it is asked for without it.
+    pub fn mpris(mut self, mpris: bool) -> Self {
+        self.options.mpris = mpris;
+        self
+    }
+
+    /// An MQTT broker as HOST[:PORT].
+    pub fn mqtt_broker(mut self, broker: &str) -> Self {
+        self.options.mqtt_broker = Some(broker.to_owned());
+        self
+    }
+
+    pub fn input(mut self, input: InputDevice, keymap: Keymap) -> Self {
+        self.options.input = Some(input);
+        self.options.keymap = keymap;
+        self
+    }
+
+    /// Where to show the display, for profiles that have one.
+    pub fn display(mut self, output: DisplayOutput) -> Self {
+        self.options.display = Some(output);
+        self
+    }
+
+    /// A file holding the output device, which is read again on a HUP signal to switch to
+    /// whatever it then holds.
+    pub fn output_config<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.options.output_config = Some(path.into());
+        self
+    }
+
+    /// Play through something other than GStreamer.
+    pub fn backend<B: Backend + 'static>(mut self, backend: B) -> Self {
+        self.options.backend = Arc::new(backend);
+        self
+    }
+
+    /// Send the player's events here.
+    pub fn events(mut self, sender: EventSender) -> Self {
+        self.options.events = Some(sender);
+        self
+    }
+
+    /// Run the player until it is told to stop.
+    pub fn run(self) -> io::Result<()> {
+        self.init()?;
+        let server = match self.server {
+            Some(server) => server,
+            None => discover()?,
+        };
+        proto::run(server, self.server_port, None, &self.name, self.options)
+    }
+
+    /// Run the player on a thread of its own.
+    pub fn spawn(self) -> io::Result<Handle> {
+        self.init()?;
+        let server = match self.server {
+            Some(server) => server,
+            None => discover()?,
+        };
+
+        let (tx, rx) = sync::mpsc::channel();
+        let thread = thread::spawn(move || {
+            let sys = actix::System::new("Storm");
+            proto::start(server, self.server_port, None, &self.name, self.options);
+            let _ = tx.send((actix::System::current(), api::Api::from_registry()));
+            sys.run()
+        });
+
+        match rx.recv() {
+            Ok((system, api)) => Ok(Handle {
+                system: system,
+                api: api,
+                thread: thread,
+            }),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Player thread failed to start",
+            )),
+        }
+    }
+
+    // Problems setting up are returned, rather than ending the process that embeds us
+    fn init(&self) -> io::Result<()> {
+        if self.options.mpris && !cfg!(feature = "mpris") {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "MPRIS was asked for, but Storm was built without the \"mpris\" feature",
+            ));
+        }
+        let backend = &self.options.backend;
+        backend
+            .init()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        backend
+            .check(&self.options.output_device)
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("Unable to use output device: {}", e),
+                )
+            })
+    }
+}
+
+/// A player running on its own thread.
+pub struct Handle {
+    system: actix::System,
+    api: actix::Addr<api::Api>,
+    thread: thread::JoinHandle<io::Result<()>>,
+}
+
+impl Handle {
+    // The connection to the server changes when the server asks us to move
+    fn proto(&self) -> Option<actix::Addr<proto::Proto>> {
+        self.api.send(api::GetProto).wait().ok().and_then(|proto| proto)
+    }
+
+    /// Returns false if the player is not connected to a server or did not take the command.
+    pub fn command(&self, command: Command) -> bool {
+        match self.proto() {
+            Some(proto) => match proto.send(command).wait() {
+                Ok(Ok(())) => true,
+                Ok(Err(e)) => {
+                    warn!("Command not carried out: {}", e);
+                    false
+                }
+                Err(_) => false,
+            },
+            None => false,
+        }
+    }
+
+    pub fn status(&self) -> Option<Status> {
+        self.proto()?.send(api::GetStatus).wait().ok()
+    }
+
+    /// Stop the player and wait for it to finish.
+    pub fn stop(self) -> io::Result<()> {
+        self.system.stop();
+        self.thread.join().unwrap_or_else(|_| {
+            Err(io::Error::new(io::ErrorKind::Other, "Player thread panicked"))
+        })
+    }
+}
@@ -1,29 +1,14 @@
 extern crate clap;
 #[macro_use]
 extern crate log;
-extern crate actix;
-extern crate bytes;
 extern crate fern;
-extern crate futures;
-extern crate gstreamer as gst;
-extern crate mac_address;
-extern crate thread_control;
-extern crate tokio_codec;
-extern crate tokio_core;
-extern crate tokio_io;
-extern crate tokio_signal;
-extern crate tokio_tcp;
-extern crate tokio_timer;
-extern crate regex;
-
-mod codec;
-mod player;
-mod proto;
+extern crate storm;
 
 use log::LevelFilter;
 
 use std::net::Ipv4Addr;
 use std::str::FromStr;
+use std::time::Duration;
 
 const VERSION: &'static str = "0.4.2";
 
@@ -72,6 +57,200 @@ fn main() {
                         .map_err(|_| format!("Unable to to parse {}", bufsize))
                 }),
         )
+        .arg(
+            clap::Arg::with_name("codecs")
+                .short("c")
+                .long("codecs")
+                .takes_value(true)
+                .value_delimiter(",")
+                .help("Comma separated list of codecs to advertise, otherwise use detection")
+                .long_help(
+                    "Override the list of codecs advertised to the server, e.g. flc,mp3,ogg.
+Codecs are given using the server's format codes:
+alc, wma, wmap, wmal, ogg, ops, flc, aac, pcm, mp3, dsf and dff.
+If not given, Storm will advertise every codec for which it finds
+a suitable GStreamer decoder.",
+                ),
+        )
+        .arg(
+            clap::Arg::with_name("capabilities")
+                .long("capabilities")
+                .takes_value(true)
+                .value_delimiter(",")
+                .help("Comma separated list of extra capabilities to send to the server")
+                .long_help(
+                    "Extra capabilities appended to those Storm sends to the server,
+given as a comma separated list of KEY=VALUE pairs,
+e.g. --capabilities HasPreAmp=1,MaxSampleRate=96000",
+                ),
+        )
+        .arg(
+            clap::Arg::with_name("model")
+                .short("m")
+                .long("model")
+                .takes_value(true)
+                .default_value("storm")
+                .possible_values(
+                    &storm::PROFILES
+                        .iter()
+                        .map(|p| p.name)
+                        .collect::<Vec<&str>>(),
+                )
+                .help("Model of player to present to the server"),
+        )
+        .arg(
+            clap::Arg::with_name("release-device")
+                .short("r")
+                .long("release-device")
+                .help("Release the output device when the player is switched off"),
+        )
+        .arg(
+            clap::Arg::with_name("idle-timeout")
+                .long("idle-timeout")
+                .takes_value(true)
+                .help("Also release the output device after being paused for this many seconds")
+                .validator(|timeout| {
+                    timeout
+                        .parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| format!("Unable to to parse {}", timeout))
+                }),
+        )
+        .arg(
+            clap::Arg::with_name("event-hook")
+                .short("e")
+                .long("event-hook")
+                .takes_value(true)
+                .help("Command to run when the player changes state")
+                .long_help(
+                    "A command, run using the shell, whenever the player is powered on or off,
+starts playing, pauses, resumes, stops or has an error.
+The event is given in the environment variable STORM_EVENT which is one of:
+power_on, power_off, play, pause, resume, stop or error.
+Also set are STORM_PLAYER_NAME, STORM_TRACK_TITLE, STORM_TRACK_ARTIST,
+STORM_TRACK_ALBUM and, for errors, STORM_ERROR.",
+                ),
+        )
+        .arg(
+            clap::Arg::with_name("api-port")
+                .short("p")
+                .long("api-port")
+                .takes_value(true)
+                .help("Serve a control and status API on this port of localhost")
+                .long_help(
+                    "Serve an HTTP control and status API on this port of localhost.
+GET /status returns the state of the player as JSON.
+GET /visualiser returns the latest visualiser levels or spectrum, in dB, as JSON.
+POST /pause, /resume, /power/on, /power/off, /volume/<0-100> and /reconnect
+control the player.
+POST /output/<device> switches the output device without stopping, the device is
+given as for --output with '#' and '/' percent-encoded, e.g. /output/alsa%23hw:1,0",
+                )
+                .validator(|port| {
+                    port.parse::<u16>()
+                        .map(|_| ())
+                        .map_err(|_| format!("Unable to to parse {}", port))
+                }),
+        )
+        .arg(
+            clap::Arg::with_name("display")
+                .long("display")
+                .takes_value(true)
+                .value_name("TARGET[#PATH]")
+                .help("Show the player's display on this target")
+                .long_help(
+                    "Claim a display and show what the server puts on it.
+The server only sends a display to models that have one: \"squeezebox\" has
+a text display, \"squeezebox2\" and \"boom\" have graphic displays.
+Allowed targets for a text display are:
+- terminal, to draw the display on standard output
+- file#/run/storm/display, to rewrite the file with the lines of each update
+- socket#/run/lcd.sock, to write the lines of each update to a Unix socket
+Allowed targets for a graphic display are:
+- terminal, to draw the display on standard output using braille characters
+- png#/run/storm/display.png, to rewrite the image on every update
+- fb or fb#/dev/fb1, to draw on a Linux framebuffer device
+Unless a model is given, \"squeezebox\" is used for a text target and
+\"squeezebox2\" for a graphic one.",
+                )
+                .validator(|spec| {
+                    storm::DisplayOutput::parse(spec.split('#').collect())
+                        .map(|_| ())
+                        .ok_or(format!("Unable to to parse {}", spec))
+                }),
+        )
+        .arg(
+            clap::Arg::with_name("input")
+                .short("i")
+                .long("input")
+                .takes_value(true)
+                .value_name("SOURCE[#PATH]")
+                .help("Send key presses from this input device to the server as remote buttons")
+                .long_help(
+                    "Send key presses from this input device to the server as if they came from
+a Squeezebox remote. Allowed sources are \"evdev\" and \"lirc\", e.g:
+- evdev#/dev/input/event3
+- lirc or lirc#/var/run/lirc/lircd",
+                )
+                .validator(|spec| {
+                    storm::InputDevice::parse(spec.split('#').collect())
+                        .map(|_| ())
+                        .ok_or(format!("Unable to to parse {}", spec))
+                }),
+        )
+        .arg(
+            clap::Arg::with_name("keymap")
+                .long("keymap")
+                .takes_value(true)
+                .requires("input")
+                .help("Map keys to remote buttons using this file")
+                .long_help(
+                    "Map keys to remote buttons using this file, which has lines of KEY = BUTTON.
+KEY is a key name as given by evdev or lirc, e.g. KEY_PLAYPAUSE, and BUTTON is
+a Squeezebox button name, e.g. pause, or an IR code, e.g. 0x768920df.
+These are added to the default mapping of the usual media keys.",
+                ),
+        )
+        .arg(
+            clap::Arg::with_name("mqtt")
+                .long("mqtt")
+                .takes_value(true)
+                .value_name("HOST[:PORT]")
+                .help("Publish state to and take commands from this MQTT broker")
+                .long_help(
+                    "Publish the state of the player to this MQTT broker and take commands from it.
+State is published as JSON to storm/<player id>/state and commands are taken from
+storm/<player id>/command, where <player id> is the MAC address without colons.
+Commands are: pause, resume, power on, power off, volume <0-100> and reconnect.
+Home Assistant discovery messages are published under homeassistant/.",
+                ),
+        )
+        .arg(
+            clap::Arg::with_name("mpris")
+                .long("mpris")
+                .help("Offer an MPRIS interface on the D-Bus session bus")
+                .long_help(
+                    "Offer an MPRIS interface on the D-Bus session bus so that desktop media keys
+and widgets can see and control the player.
+Storm must be built with the \"mpris\" feature for this to be available.",
+                ),
+        )
+        .arg(
+            clap::Arg::with_name("cli-port")
+                .long("cli-port")
+                .takes_value(true)
+                .default_value("9090")
+                .help("Port of the server's command line interface")
+                .long_help(
+                    "Port of the server's command line interface, which requests from MPRIS
+are sent to.",
+                )
+                .validator(|port| {
+                    port.parse::<u16>()
+                        .map(|_| ())
+                        .map_err(|_| format!("Unable to to parse {}", port))
+                }),
+        )
         .arg(
             clap::Arg::with_name("output-device")
                 .short("o")
@@ -81,15 +260,45 @@ fn main() {
                 .value_delimiter("#")
                 .long_help(
                     "The output device is specified using SERVICE[#DEVICE], e.g. alsa#hw:0,0.
-Allowed services are: \"auto\", \"alsa\" and \"pulse\".
+Allowed services are: \"auto\", \"alsa\", \"pulse\", \"file\", \"stdout\" and \"gst\".
 Selecting \"auto\" will let Storm choose the best device.
 For \"alsa\" and \"pulse\" the device is specified as usual for those services,
 e.g:
 - alsa#default, alsa#plughw:0,0 or even alsa#pulse
 - pulse#alsa_output.pci-0000_00_1f.3.analog-stereo
-If no device is specified the default for that service is used.",
+If no device is specified the default for that service is used.
+For \"file\" the device is a path, a .wav or .flac file is started again each
+time the server stops playing. Anything else, such as a FIFO, is sent raw PCM
+and added to. Raw PCM is written at the pace the audio would be heard, e.g:
+- file#/tmp/out.flac
+- file#/tmp/snapfifo#S16LE:48000:2
+- stdout#S32LE:44100:2
+The raw format is FORMAT[:RATE[:CHANNELS]], by default S16LE:44100:2.
+For \"gst\" the device is a GStreamer pipeline, as for gst-launch-1.0, that ends
+in a sink. The audio goes to its first free pad, e.g:
+- gst#pipewiresink
+- gst#jackaudiosink connect=none
+- gst#audioconvert ! rtpL16pay ! udpsink host=192.168.1.20 port=5004",
                 ),
         )
+        .arg(
+            clap::Arg::with_name("output-config")
+                .long("output-config")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Read the output device from this file, and again on a HUP signal")
+                .long_help(
+                    "Read the output device from this file, written as for --output, in place of
+--output. On a HUP signal the file is read again and Storm switches to the device
+it holds without stopping. Without this option a HUP signal opens the same output
+device again, e.g. after it has been plugged back in.",
+                ),
+        )
+        .arg(
+            clap::Arg::with_name("list-outputs")
+                .long("list-outputs")
+                .help("List the output devices that can be given to --output, then exit"),
+        )
         .get_matches();
 
     let log_level = match opts.value_of("log-level") {
@@ -116,36 +325,144 @@ If no device is specified the default for that service is used.",
         .apply()
         .expect("Failure setting up logger");
 
-    let server_addr = match opts.value_of("server") {
-        Some(ip_s) => Ipv4Addr::from_str(ip_s).unwrap(),
-        None => proto::discover().unwrap_or_else(|e| {
-            error!("Network error whilst looking for server: {}, exiting.", e);
-            std::process::exit(1);
-        }),
-    };
+    if opts.is_present("mpris") && !cfg!(feature = "mpris") {
+        clap::Error::with_description(
+            "--mpris is not available as Storm was built without the \"mpris\" feature",
+            clap::ErrorKind::InvalidValue,
+        )
+        .exit();
+    }
+
+    if opts.is_present("list-outputs") {
+        for output in storm::list_outputs() {
+            println!("{}\n    {}", output.device, output.description);
+            if !output.formats.is_empty() {
+                println!("    Formats: {}", output.formats.join(", "));
+            }
+            if !output.rates.is_empty() {
+                println!("    Rates: {}", output.rates.join(", "));
+            }
+        }
+        return;
+    }
 
-    let bufsize = opts
-        .value_of("buffersize")
-        .map(|b| b.parse::<u32>().unwrap());
+    let mut builder = storm::Builder::new().name(opts.value_of("name").unwrap());
+
+    match opts.value_of("server") {
+        Some(ip_s) => {
+            let server_addr = Ipv4Addr::from_str(ip_s).unwrap();
+            info!("Using server address: {}", server_addr);
+            builder = builder.server(server_addr);
+        }
+        None => info!("No server given, will look for one"),
+    }
 
-    info!("Using server address: {}", server_addr);
     info!("Name of player is: {}", opts.value_of("name").unwrap());
-    if let Some(bufsize) = bufsize {
+    if let Some(bufsize) = opts.value_of("buffersize") {
         info!("Input buffer size is: {} KiB", bufsize);
+        builder = builder.buffer_size(bufsize.parse::<u32>().unwrap());
     }
 
-    match proto::run(
-        server_addr,
-        None,
-        opts.value_of("name").unwrap(),
-        bufsize,
-        player::AudioDevice::from(
+    let display = opts
+        .value_of("display")
+        .and_then(|spec| storm::DisplayOutput::parse(spec.split('#').collect()));
+
+    // The display needs a model the server knows to have one
+    let model = match display {
+        Some(storm::DisplayOutput::Png(_)) | Some(storm::DisplayOutput::Framebuffer(_))
+            if opts.occurrences_of("model") == 0 =>
+        {
+            "squeezebox2"
+        }
+        Some(_) if opts.occurrences_of("model") == 0 => "squeezebox",
+        _ => opts.value_of("model").unwrap(),
+    };
+    if display.is_some() && opts.occurrences_of("model") == 0 {
+        warn!(
+            "Pretending to be a {} so that the server sends a display, use --model to choose",
+            model
+        );
+    }
+    let profile = storm::profile(model).unwrap();
+    let output = match opts.value_of("output-config") {
+        Some(path) => {
+            builder = builder.output_config(path);
+            storm::AudioDevice::load(path).unwrap_or_else(|e| {
+                error!("Unable to read output device from {}: {}, exiting.", path, e);
+                std::process::exit(1);
+            })
+        }
+        None => storm::AudioDevice::from(
             opts.values_of("output-device")
                 .unwrap()
                 .collect::<Vec<&str>>(),
         ),
-    ) {
+    };
+    if let Some(display) = display {
+        if profile.display.is_none() {
+            warn!("The {} model has no display", model);
+        }
+        if let (storm::DisplayOutput::Terminal, true) = (&display, output.is_stdout()) {
+            error!("The display and the audio cannot both go to standard output, exiting.");
+            std::process::exit(1);
+        }
+        builder = builder.display(display);
+    }
+
+    builder = builder
+        .output(output)
+        .profile(profile)
+        .device_release(storm::DeviceRelease {
+            power_off: opts.is_present("release-device") || opts.is_present("idle-timeout"),
+            idle_timeout: opts
+                .value_of("idle-timeout")
+                .map(|t| Duration::from_secs(t.parse::<u64>().unwrap())),
+        })
+        .mpris(opts.is_present("mpris"));
+
+    if let Some(codecs) = opts.values_of("codecs") {
+        builder = builder.codecs(codecs.map(|c| c.to_lowercase()).collect());
+    }
+
+    if let Some(caps) = opts.values_of("capabilities") {
+        builder = builder.capabilities(caps.map(|c| c.to_owned()).collect());
+    }
+
+    if let Some(command) = opts.value_of("event-hook") {
+        builder = builder.event_hook(command);
+    }
+
+    if let Some(port) = opts.value_of("api-port") {
+        builder = builder.api_port(port.parse::<u16>().unwrap());
+    }
+
+    if let Some(port) = opts.value_of("cli-port") {
+        builder = builder.cli_port(port.parse::<u16>().unwrap());
+    }
+
+    if let Some(broker) = opts.value_of("mqtt") {
+        builder = builder.mqtt_broker(broker);
+    }
+
+    if let Some(input) = opts
+        .value_of("input")
+        .and_then(|spec| storm::InputDevice::parse(spec.split('#').collect()))
+    {
+        let keymap = match opts.value_of("keymap") {
+            Some(path) => storm::Keymap::load(path).unwrap_or_else(|e| {
+                error!("Unable to load keymap {}: {}, exiting.", path, e);
+                std::process::exit(1);
+            }),
+            None => storm::Keymap::default(),
+        };
+        builder = builder.input(input, keymap);
+    }
+
+    match builder.run() {
         Ok(_) => info!("Storm terminated normally"),
-        e @ _ => error!("Storm error {:?}", e),
+        e @ _ => {
+            error!("Storm error {:?}", e);
+            std::process::exit(2);
+        }
     };
 }
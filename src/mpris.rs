@@ -0,0 +1,508 @@
+use actix;
+use actix::{Actor, Context};
+use dbus;
+use dbus::arg::{RefArg, Variant};
+use dbus::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged;
+use dbus::tree::{Access, Factory};
+use dbus::{BusType, Connection, Message, NameFlag, Path, SignalArgs};
+
+use player;
+use attach;
+
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+// How far a position can be from where it is expected before it counts as a seek, in us
+const SEEK_THRESHOLD: i64 = 1_000_000;
+
+pub enum Request {
+    Play,
+    Pause,
+    PlayPause,
+    Stop,
+    Next,
+    Previous,
+    Volume(f64),
+    // Microseconds, from where the track is or from its start
+    Seek(i64),
+    SetPosition(i64),
+}
+
+impl actix::Message for Request {
+    type Result = ();
+}
+
+#[derive(Clone)]
+pub struct Update {
+    pub status: &'static str,
+    pub volume: f64,
+    // Milliseconds into the track
+    pub position: u64,
+    pub track: player::TrackInfo,
+}
+
+impl actix::Message for Update {
+    type Result = ();
+}
+
+pub struct Start(pub String);
+
+impl actix::Message for Start {
+    type Result = ();
+}
+
+#[derive(Default)]
+pub struct Mpris {
+    proto: attach::Connection,
+    updates: Option<mpsc::Sender<Update>>,
+}
+
+impl Actor for Mpris {
+    type Context = Context<Self>;
+}
+
+impl actix::Supervised for Mpris {}
+
+impl actix::SystemService for Mpris {}
+
+impl actix::Handler<Start> for Mpris {
+    type Result = ();
+
+    fn handle(&mut self, msg: Start, ctx: &mut Context<Self>) {
+        let (tx, rx) = mpsc::channel();
+        self.updates = Some(tx);
+        let mpris = actix::AsyncContext::address(ctx);
+        let name = msg.0;
+        thread::spawn(move || {
+            if let Err(e) = serve(&name, move |request| mpris.do_send(request), rx) {
+                error!("MPRIS service failed: {}", e);
+            }
+        });
+    }
+}
+
+impl actix::Handler<attach::Attach> for Mpris {
+    type Result = ();
+
+    fn handle(&mut self, msg: attach::Attach, _ctx: &mut Context<Self>) {
+        self.proto.attach(msg);
+    }
+}
+
+impl actix::Handler<Request> for Mpris {
+    type Result = ();
+
+    fn handle(&mut self, msg: Request, _ctx: &mut Context<Self>) {
+        self.proto.forward(msg);
+    }
+}
+
+impl actix::Handler<Update> for Mpris {
+    type Result = ();
+
+    fn handle(&mut self, msg: Update, _ctx: &mut Context<Self>) {
+        if let Some(ref updates) = self.updates {
+            let _ = updates.send(msg);
+        }
+    }
+}
+
+// Runs until there are no more updates to come
+fn serve<F>(name: &str, requests: F, updates: mpsc::Receiver<Update>) -> Result<(), dbus::Error>
+where
+    F: Fn(Request) + Clone + 'static,
+{
+    let conn = Connection::get_private(BusType::Session)?;
+    let bus_name = format!(
+        "org.mpris.MediaPlayer2.storm.{}",
+        name.chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect::<String>()
+    );
+    conn.register_name(&bus_name, NameFlag::DoNotQueue as u32)?;
+    info!("MPRIS service registered as: {}", bus_name);
+
+    // Along with when it was last updated, so the position can be worked out in between
+    let state = Arc::new(Mutex::new((
+        Update {
+            status: "Stopped",
+            volume: 1.0,
+            position: 0,
+            track: player::TrackInfo::default(),
+        },
+        Instant::now(),
+    )));
+
+    let f = Factory::new_fn::<()>();
+
+    let root = {
+        let identity = name.to_owned();
+        f.interface("org.mpris.MediaPlayer2", ())
+            .add_m(f.method("Raise", (), |m| Ok(vec![m.msg.method_return()])))
+            .add_m(f.method("Quit", (), |m| Ok(vec![m.msg.method_return()])))
+            .add_p(f.property::<bool, _>("CanQuit", ()).on_get(|i, _| {
+                i.append(false);
+                Ok(())
+            }))
+            .add_p(f.property::<bool, _>("CanRaise", ()).on_get(|i, _| {
+                i.append(false);
+                Ok(())
+            }))
+            .add_p(f.property::<bool, _>("HasTrackList", ()).on_get(|i, _| {
+                i.append(false);
+                Ok(())
+            }))
+            .add_p(f.property::<&str, _>("Identity", ()).on_get(move |i, _| {
+                i.append(identity.as_str());
+                Ok(())
+            }))
+            .add_p(
+                f.property::<Vec<&str>, _>("SupportedUriSchemes", ())
+                    .on_get(|i, _| {
+                        i.append(Vec::<&str>::new());
+                        Ok(())
+                    }),
+            )
+            .add_p(
+                f.property::<Vec<&str>, _>("SupportedMimeTypes", ())
+                    .on_get(|i, _| {
+                        i.append(Vec::<&str>::new());
+                        Ok(())
+                    }),
+            )
+    };
+
+    let method = |name: &'static str, request: fn() -> Request| {
+        let requests = requests.clone();
+        f.method(name, (), move |m| {
+            requests(request());
+            Ok(vec![m.msg.method_return()])
+        })
+    };
+
+    let player_iface = {
+        let status_state = state.clone();
+        let metadata_state = state.clone();
+        let volume_state = state.clone();
+        let position_state = state.clone();
+        let volume_requests = requests.clone();
+        let seek_requests = requests.clone();
+        let position_requests = requests.clone();
+        f.interface(PLAYER_INTERFACE, ())
+            .add_m(method("Play", || Request::Play))
+            .add_m(method("Pause", || Request::Pause))
+            .add_m(method("PlayPause", || Request::PlayPause))
+            .add_m(method("Stop", || Request::Stop))
+            .add_m(method("Next", || Request::Next))
+            .add_m(method("Previous", || Request::Previous))
+            .add_m(
+                f.method("Seek", (), move |m| {
+                    seek_requests(Request::Seek(m.msg.read1()?));
+                    Ok(vec![m.msg.method_return()])
+                })
+                .inarg::<i64, _>("Offset"),
+            )
+            .add_m(
+                f.method("SetPosition", (), move |m| {
+                    let (_, position): (Path, i64) = m.msg.read2()?;
+                    if position >= 0 {
+                        position_requests(Request::SetPosition(position));
+                    }
+                    Ok(vec![m.msg.method_return()])
+                })
+                .inarg::<Path, _>("TrackId")
+                .inarg::<i64, _>("Position"),
+            )
+            .add_p(
+                f.property::<&str, _>("PlaybackStatus", ())
+                    .on_get(move |i, _| {
+                        i.append(status_state.lock().unwrap().0.status);
+                        Ok(())
+                    }),
+            )
+            .add_p(
+                f.property::<HashMap<String, Variant<Box<dyn RefArg>>>, _>("Metadata", ())
+                    .on_get(move |i, _| {
+                        i.append(metadata(&metadata_state.lock().unwrap().0.track));
+                        Ok(())
+                    }),
+            )
+            .add_p(
+                f.property::<f64, _>("Volume", ())
+                    .access(Access::ReadWrite)
+                    .on_get(move |i, _| {
+                        i.append(volume_state.lock().unwrap().0.volume);
+                        Ok(())
+                    })
+                    .on_set(move |i, _| {
+                        let volume: f64 = i.read()?;
+                        volume_requests(Request::Volume(volume.max(0.0).min(1.0)));
+                        Ok(())
+                    }),
+            )
+            .add_p(f.property::<f64, _>("Rate", ()).on_get(|i, _| {
+                i.append(1.0);
+                Ok(())
+            }))
+            .add_p(f.property::<f64, _>("MinimumRate", ()).on_get(|i, _| {
+                i.append(1.0);
+                Ok(())
+            }))
+            .add_p(f.property::<f64, _>("MaximumRate", ()).on_get(|i, _| {
+                i.append(1.0);
+                Ok(())
+            }))
+            .add_p(f.property::<i64, _>("Position", ()).on_get(move |i, _| {
+                let state = position_state.lock().unwrap();
+                i.append(position(&state.0, state.1));
+                Ok(())
+            }))
+            .add_p(f.property::<bool, _>("CanGoNext", ()).on_get(|i, _| {
+                i.append(true);
+                Ok(())
+            }))
+            .add_p(f.property::<bool, _>("CanGoPrevious", ()).on_get(|i, _| {
+                i.append(true);
+                Ok(())
+            }))
+            .add_p(f.property::<bool, _>("CanPlay", ()).on_get(|i, _| {
+                i.append(true);
+                Ok(())
+            }))
+            .add_p(f.property::<bool, _>("CanPause", ()).on_get(|i, _| {
+                i.append(true);
+                Ok(())
+            }))
+            .add_p(f.property::<bool, _>("CanSeek", ()).on_get(|i, _| {
+                i.append(true);
+                Ok(())
+            }))
+            .add_p(f.property::<bool, _>("CanControl", ()).on_get(|i, _| {
+                i.append(true);
+                Ok(())
+            }))
+    };
+
+    let tree = f.tree(()).add(
+        f.object_path(MPRIS_PATH, ())
+            .introspectable()
+            .add(root)
+            .add(player_iface),
+    );
+    tree.set_registered(&conn, true)?;
+    conn.add_handler(tree);
+
+    loop {
+        conn.incoming(100).next();
+
+        // Tell anyone watching about any changes since we last looked
+        loop {
+            let update = match updates.try_recv() {
+                Ok(update) => update,
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => return Ok(()),
+            };
+            let mut changed: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+            let mut seeked = None;
+            {
+                let mut state = state.lock().unwrap();
+                let (ref mut current, ref mut updated) = *state;
+                if current.status != update.status {
+                    changed.insert(
+                        "PlaybackStatus".to_owned(),
+                        Variant(Box::new(update.status.to_owned())),
+                    );
+                }
+                if (current.volume - update.volume).abs() > 0.001 {
+                    changed.insert("Volume".to_owned(), Variant(Box::new(update.volume)));
+                }
+                if current.track.title != update.track.title
+                    || current.track.artist != update.track.artist
+                    || current.track.album != update.track.album
+                {
+                    changed.insert(
+                        "Metadata".to_owned(),
+                        Variant(Box::new(metadata(&update.track))),
+                    );
+                } else {
+                    // Positions are only asked for, unless they jump about within a track
+                    let expected = position(current, *updated);
+                    let actual = update.position as i64 * 1000;
+                    if (actual - expected).abs() > SEEK_THRESHOLD {
+                        seeked = Some(actual);
+                    }
+                }
+                *current = update;
+                *updated = Instant::now();
+            }
+
+            if !changed.is_empty() {
+                let signal = PropertiesPropertiesChanged {
+                    interface_name: PLAYER_INTERFACE.to_owned(),
+                    changed_properties: changed,
+                    invalidated_properties: Vec::new(),
+                };
+                let _ = conn.send(signal.to_emit_message(&Path::from(MPRIS_PATH)));
+            }
+            if let Some(position) = seeked {
+                if let Ok(signal) = Message::new_signal(MPRIS_PATH, PLAYER_INTERFACE, "Seeked") {
+                    let _ = conn.send(signal.append1(position));
+                }
+            }
+        }
+    }
+}
+
+// Microseconds into the track, moving on from the last update whilst playing
+fn position(current: &Update, updated: Instant) -> i64 {
+    let mut position = current.position * 1000;
+    if current.status == "Playing" {
+        let elapsed = updated.elapsed();
+        position += elapsed.as_secs() * 1_000_000 + elapsed.subsec_micros() as u64;
+    }
+    position as i64
+}
+
+fn metadata(track: &player::TrackInfo) -> HashMap<String, Variant<Box<dyn RefArg>>> {
+    let mut metadata: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+    metadata.insert(
+        "mpris:trackid".to_owned(),
+        Variant(Box::new(Path::from("/org/mpris/MediaPlayer2/Track/current"))),
+    );
+    if let Some(ref title) = track.title {
+        metadata.insert("xesam:title".to_owned(), Variant(Box::new(title.clone())));
+    }
+    if let Some(ref artist) = track.artist {
+        metadata.insert(
+            "xesam:artist".to_owned(),
+            Variant(Box::new(vec![artist.clone()])),
+        );
+    }
+    if let Some(ref album) = track.album {
+        metadata.insert("xesam:album".to_owned(), Variant(Box::new(album.clone())));
+    }
+    metadata
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dbus::stdintf::org_freedesktop_dbus::Properties;
+    use testing;
+
+    use std::env;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use std::time::Duration;
+
+    const BUS_NAME: &str = "org.mpris.MediaPlayer2.storm.Test_Player";
+
+    // A session bus of our own, so that nothing on the desktop is involved
+    struct Bus(Child);
+
+    impl Bus {
+        fn start() -> Self {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(&["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .spawn()
+                .expect("dbus-daemon is needed to test MPRIS");
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap())
+                .read_line(&mut address)
+                .unwrap();
+            env::set_var("DBUS_SESSION_BUS_ADDRESS", address.trim());
+            Bus(daemon)
+        }
+    }
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    fn call(conn: &Connection, method: &str, build: fn(Message) -> Message) {
+        let msg = Message::new_method_call(BUS_NAME, MPRIS_PATH, PLAYER_INTERFACE, method).unwrap();
+        conn.send_with_reply_and_block(build(msg), 1000).unwrap();
+    }
+
+    #[test]
+    fn player_is_served_on_the_bus() {
+        let _bus = Bus::start();
+        let (requests_tx, requests) = mpsc::channel();
+        let (updates, updates_rx) = mpsc::channel();
+        let service = thread::spawn(move || {
+            let requested = move |request| {
+                let _ = requests_tx.send(request);
+            };
+            serve("Test Player", requested, updates_rx).unwrap();
+        });
+
+        let conn = Connection::get_private(BusType::Session).unwrap();
+        let remote = conn.with_path(BUS_NAME, MPRIS_PATH, 1000);
+        let get = |property: &str| remote.get(PLAYER_INTERFACE, property).ok();
+
+        testing::wait_for(|| {
+            get("PlaybackStatus").map_or(false, |status| status.0.as_str() == Some("Stopped"))
+        });
+
+        updates
+            .send(Update {
+                status: "Paused",
+                volume: 0.25,
+                position: 5_000,
+                track: player::TrackInfo::default(),
+            })
+            .unwrap();
+        testing::wait_for(|| get("Position").and_then(|p| p.0.as_i64()) == Some(5_000_000));
+        assert_eq!(
+            get("PlaybackStatus").and_then(|s| s.0.as_str().map(|s| s.to_owned())),
+            Some("Paused".to_owned())
+        );
+
+        let next = || requests.recv_timeout(Duration::from_secs(1)).ok();
+
+        call(&conn, "PlayPause", |msg| msg);
+        match next() {
+            Some(Request::PlayPause) => (),
+            _ => panic!("Expected play/pause"),
+        }
+
+        call(&conn, "Seek", |msg| msg.append1(-2_000_000i64));
+        match next() {
+            Some(Request::Seek(-2_000_000)) => (),
+            _ => panic!("Expected a seek back by two seconds"),
+        }
+
+        call(&conn, "SetPosition", |msg| {
+            msg.append2(Path::from("/org/mpris/MediaPlayer2/Track/current"), 10_000_000i64)
+        });
+        match next() {
+            Some(Request::SetPosition(10_000_000)) => (),
+            _ => panic!("Expected a move to ten seconds in"),
+        }
+
+        remote
+            .set(
+                PLAYER_INTERFACE,
+                "Volume",
+                Variant(Box::new(0.5f64) as Box<dyn RefArg>),
+            )
+            .unwrap();
+        match next() {
+            Some(Request::Volume(volume)) => assert_eq!(volume, 0.5),
+            _ => panic!("Expected a change of volume"),
+        }
+
+        // The service stops once there is nothing left to tell it
+        drop(updates);
+        service.join().unwrap();
+    }
+}
@@ -0,0 +1,368 @@
+use actix;
+use actix::{Actor, ActorFuture, AsyncContext, Context, WrapFuture};
+use rumqtt::{LastWill, MqttClient, MqttOptions, Notification, QoS};
+use serde_json;
+
+use api;
+use attach;
+
+use std::thread;
+
+const DEFAULT_PORT: u16 = 1883;
+const DISCOVERY_PREFIX: &str = "homeassistant";
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+pub struct Start {
+    pub broker: String,
+    pub player_id: String,
+}
+
+impl actix::Message for Start {
+    type Result = ();
+}
+
+struct Connected(MqttClient);
+
+impl actix::Message for Connected {
+    type Result = ();
+}
+
+// Sent whenever the player's state changes, so it is published without having to keep asking
+pub struct Changed;
+
+impl actix::Message for Changed {
+    type Result = ();
+}
+
+#[derive(Serialize, PartialEq)]
+struct State {
+    power: &'static str,
+    state: &'static str,
+    volume: u8,
+    buffer_fullness: u32,
+    output_buffer_fullness: u32,
+    output_underruns: u32,
+    input_underruns: u32,
+    title: Option<String>,
+    artist: Option<String>,
+}
+
+#[derive(Default)]
+pub struct Mqtt {
+    proto: attach::Connection,
+    client: Option<MqttClient>,
+    player_id: String,
+    name: Option<String>,
+    state: Option<State>,
+}
+
+impl Actor for Mqtt {
+    type Context = Context<Self>;
+}
+
+impl actix::Supervised for Mqtt {}
+
+impl actix::SystemService for Mqtt {}
+
+impl actix::Handler<Start> for Mqtt {
+    type Result = ();
+
+    fn handle(&mut self, msg: Start, ctx: &mut Context<Self>) {
+        let mut broker = msg.broker.splitn(2, ':');
+        let host = broker.next().unwrap_or("localhost").to_owned();
+        let port = broker
+            .next()
+            .and_then(|port| port.parse::<u16>().ok())
+            .unwrap_or(DEFAULT_PORT);
+
+        self.player_id = msg.player_id;
+        let options = MqttOptions::new(format!("storm_{}", self.player_id), host.as_str(), port)
+            .set_keep_alive(30)
+            .set_last_will(LastWill {
+                topic: self.topic("available"),
+                message: OFFLINE.to_owned(),
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            });
+
+        // Connecting waits on the broker, which may not answer, so it is done on a thread of its
+        // own that then carries on to receive commands
+        let mqtt = ctx.address();
+        thread::spawn(move || {
+            let (client, notifications) = match MqttClient::start(options) {
+                Ok(started) => started,
+                Err(e) => {
+                    error!("Unable to connect to MQTT broker {}:{}: {:?}", host, port, e);
+                    return;
+                }
+            };
+            info!("Connected to MQTT broker: {}:{}", host, port);
+            mqtt.do_send(Connected(client));
+
+            for notification in notifications {
+                if let Notification::Publish(publish) = notification {
+                    let payload = String::from_utf8_lossy(&publish.payload).to_string();
+                    match parse_command(&payload) {
+                        Some(command) => mqtt.do_send(command),
+                        None => warn!("Unrecognised MQTT command: {}", payload),
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl actix::Handler<Connected> for Mqtt {
+    type Result = ();
+
+    fn handle(&mut self, msg: Connected, ctx: &mut Context<Self>) {
+        let mut client = msg.0;
+        if let Err(e) = client.subscribe(self.topic("command"), QoS::AtLeastOnce) {
+            warn!("Unable to subscribe to MQTT commands: {:?}", e);
+        }
+        self.client = Some(client);
+        self.publish("available", ONLINE, true);
+        self.fetch_state(ctx);
+    }
+}
+
+impl actix::Handler<attach::Attach> for Mqtt {
+    type Result = ();
+
+    fn handle(&mut self, msg: attach::Attach, ctx: &mut Context<Self>) {
+        self.proto.attach(msg);
+        self.fetch_state(ctx);
+    }
+}
+
+impl actix::Handler<Changed> for Mqtt {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Changed, ctx: &mut Context<Self>) {
+        self.fetch_state(ctx);
+    }
+}
+
+impl actix::Handler<api::Command> for Mqtt {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: api::Command, ctx: &mut Context<Self>) -> Self::Result {
+        let proto = match self.proto.current() {
+            Some(proto) => proto,
+            None => return Err("Not connected".to_owned()),
+        };
+        ctx.spawn(
+            proto
+                .send(msg)
+                .into_actor(self)
+                .map(|result, _, _| {
+                    if let Err(e) = result {
+                        warn!("Unable to carry out MQTT command: {}", e);
+                    }
+                })
+                .map_err(|_, _, _| ()),
+        );
+        Ok(())
+    }
+}
+
+impl Mqtt {
+    fn topic(&self, topic: &str) -> String {
+        format!("storm/{}/{}", self.player_id, topic)
+    }
+
+    fn publish(&mut self, topic: &str, payload: &str, retain: bool) {
+        let topic = self.topic(topic);
+        if let Some(ref mut client) = self.client {
+            if let Err(e) = client.publish(topic, QoS::AtLeastOnce, retain, payload) {
+                warn!("Unable to publish to MQTT broker: {:?}", e);
+            }
+        }
+    }
+
+    fn fetch_state(&mut self, ctx: &mut Context<Self>) {
+        if let Some(proto) = self.proto.current() {
+            ctx.spawn(
+                proto
+                    .send(api::GetStatus)
+                    .into_actor(self)
+                    .map(|status, mqtt, _| mqtt.update_state(status))
+                    .map_err(|_, _, _| ()),
+            );
+        }
+    }
+
+    // Only publish when something has changed, the elapsed time is left out for this reason
+    fn update_state(&mut self, status: api::Status) {
+        // Nothing is remembered until there is a broker to tell
+        if self.client.is_none() {
+            return;
+        }
+
+        if self.name.as_ref() != Some(&status.name) {
+            self.publish_discovery(&status.name);
+            self.name = Some(status.name.clone());
+        }
+
+        let state = State {
+            power: if status.power { "on" } else { "off" },
+            state: status.state,
+            volume: status.volume,
+            buffer_fullness: status.buffer_fullness,
+            output_buffer_fullness: status.output_buffer_fullness,
+            output_underruns: status.output_underruns,
+            input_underruns: status.input_underruns,
+            title: status.title,
+            artist: status.artist,
+        };
+        if self.state.as_ref() == Some(&state) {
+            return;
+        }
+
+        if let Ok(payload) = serde_json::to_string(&state) {
+            self.publish("state", &payload, true);
+        }
+        self.state = Some(state);
+    }
+
+    // Describe the player to Home Assistant so it appears without any configuration
+    fn publish_discovery(&mut self, name: &str) {
+        let device = json!({
+            "identifiers": [format!("storm_{}", self.player_id)],
+            "name": name,
+            "manufacturer": "Storm",
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        });
+        let entities = &[
+            ("switch", "power", "Power", "{{ value_json.power }}"),
+            ("sensor", "state", "State", "{{ value_json.state }}"),
+            ("sensor", "volume", "Volume", "{{ value_json.volume }}"),
+            ("sensor", "buffer", "Buffer", "{{ value_json.buffer_fullness }}"),
+            ("sensor", "title", "Title", "{{ value_json.title }}"),
+        ];
+
+        for (component, object, label, template) in entities {
+            let mut config = json!({
+                "name": format!("{} {}", name, label),
+                "unique_id": format!("storm_{}_{}", self.player_id, object),
+                "state_topic": self.topic("state"),
+                "value_template": template,
+                "availability_topic": self.topic("available"),
+                "device": device.clone(),
+            });
+            if *component == "switch" {
+                config["command_topic"] = json!(self.topic("command"));
+                config["payload_on"] = json!("power on");
+                config["payload_off"] = json!("power off");
+                config["state_on"] = json!("on");
+                config["state_off"] = json!("off");
+            } else if *object == "volume" {
+                config["unit_of_measurement"] = json!("%");
+            }
+
+            let topic = format!(
+                "{}/{}/storm_{}/{}/config",
+                DISCOVERY_PREFIX, component, self.player_id, object
+            );
+            if let Some(ref mut client) = self.client {
+                if let Err(e) =
+                    client.publish(topic, QoS::AtLeastOnce, true, config.to_string())
+                {
+                    warn!("Unable to publish to MQTT broker: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+// Commands are the same as for the control API, e.g. "pause" or "volume 50"
+fn parse_command(payload: &str) -> Option<api::Command> {
+    let words: Vec<&str> = payload.split_whitespace().collect();
+    match words.as_slice() {
+        ["pause"] => Some(api::Command::Pause),
+        ["resume"] => Some(api::Command::Resume),
+        ["power", "on"] => Some(api::Command::Power(true)),
+        ["power", "off"] => Some(api::Command::Power(false)),
+        ["volume", level] => match level.parse::<u8>() {
+            Ok(level) if level <= 100 => Some(api::Command::Volume(level)),
+            _ => None,
+        },
+        ["reconnect"] => Some(api::Command::Reconnect),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_parses<F: Fn(&api::Command) -> bool>(payload: &str, expected: F) {
+        match parse_command(payload) {
+            Some(ref command) if expected(command) => (),
+            Some(_) => panic!("Wrong command from: {:?}", payload),
+            None => panic!("No command from: {:?}", payload),
+        }
+    }
+
+    #[test]
+    fn commands_are_parsed() {
+        assert_parses("pause", |c| match *c {
+            api::Command::Pause => true,
+            _ => false,
+        });
+        assert_parses("resume", |c| match *c {
+            api::Command::Resume => true,
+            _ => false,
+        });
+        assert_parses("power on", |c| match *c {
+            api::Command::Power(true) => true,
+            _ => false,
+        });
+        assert_parses("power off", |c| match *c {
+            api::Command::Power(false) => true,
+            _ => false,
+        });
+        assert_parses("reconnect", |c| match *c {
+            api::Command::Reconnect => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn volumes_are_parsed() {
+        for &(payload, level) in &[
+            ("volume 0", 0),
+            ("volume 50", 50),
+            ("volume 100", 100),
+            ("  volume\t25 \n", 25),
+        ] {
+            assert_parses(payload, |c| match *c {
+                api::Command::Volume(parsed) => parsed == level,
+                _ => false,
+            });
+        }
+    }
+
+    #[test]
+    fn bad_volumes_are_rejected() {
+        for payload in &[
+            "volume 101",
+            "volume 256",
+            "volume -1",
+            "volume 5.5",
+            "volume loud",
+            "volume",
+            "volume 50 60",
+        ] {
+            assert!(parse_command(payload).is_none(), "Accepted: {:?}", payload);
+        }
+    }
+
+    #[test]
+    fn unknown_commands_are_rejected() {
+        for payload in &["", "play", "PAUSE", "power", "power maybe", "pause now"] {
+            assert!(parse_command(payload).is_none(), "Accepted: {:?}", payload);
+        }
+    }
+}
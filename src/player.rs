@@ -1,28 +1,120 @@
 use actix;
+use alsa;
+use actix::{ActorContext, AsyncContext};
 use gst::prelude::{
-    Cast, ElementExt, ElementExtManual, GstBinExt, GstBinExtManual, GstObjectExt, ObjectExt,
-    PadExt, PadExtManual,
+    Cast, ClockExt, DeviceExt, DeviceMonitorExt, ElementExt, ElementExtManual,
+    GObjectExtManualGst, GstBinExt, GstBinExtManual, GstObjectExt, ObjectExt, PadExt,
+    PadExtManual, PipelineExt,
 };
+use glib;
 use gst::MessageView;
 use thread_control;
 
-use proto;
-
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io;
 use std::net::Ipv4Addr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time;
 
-#[derive(Copy, Clone)]
+// How long audio that is playing takes at most to reach the block in front of the sink, in ms
+const PARK_TIMEOUT: u64 = 500;
+// How long an encoder has to write out the end of a file when stopping, in ms
+const FINISH_TIMEOUT: u64 = 2000;
+// How often the visualiser taps report, in nanoseconds
+const VISUALISER_INTERVAL: u64 = 50_000_000;
+// The quietest sound the visualiser shows, in dB
+pub const VISUALISER_FLOOR: i32 = -60;
+
+// Sample formats that can be asked for on raw outputs, as GStreamer names them
+const RAW_FORMATS: &[&str] = &[
+    "S16LE", "S16BE", "S24LE", "S24BE", "S24_32LE", "S24_32BE", "S32LE", "S32BE", "F32LE",
+    "F32BE", "F64LE", "F64BE",
+];
+
+#[derive(Copy, Clone, Debug)]
 enum AudioService {
     Auto,
     Alsa,
     Pulse,
+    // The device is the path, what is written depends on its extension
+    File,
+    Stdout,
+    // The device is a pipeline description that takes the audio
+    Gst,
+}
+
+// The format of raw PCM outputs, given as FORMAT[:RATE[:CHANNELS]], e.g. S16LE:44100:2
+#[derive(Clone, Debug)]
+struct RawFormat {
+    format: String,
+    rate: u32,
+    channels: u32,
+}
+
+impl Default for RawFormat {
+    fn default() -> Self {
+        RawFormat {
+            format: "S16LE".to_owned(),
+            rate: 44100,
+            channels: 2,
+        }
+    }
 }
 
-#[derive(Clone)]
+impl RawFormat {
+    fn parse(spec: Option<&&str>) -> Self {
+        let spec = match spec {
+            Some(spec) => spec,
+            None => return RawFormat::default(),
+        };
+
+        let mut fields = spec.split(':');
+        let format = fields.next().unwrap_or("").to_uppercase();
+        let rate = fields.next().map(|rate| rate.parse::<u32>());
+        let channels = fields.next().map(|channels| channels.parse::<u32>());
+        match (RAW_FORMATS.contains(&format.as_str()), rate, channels) {
+            (true, None, None) => RawFormat {
+                format: format,
+                ..RawFormat::default()
+            },
+            (true, Some(Ok(rate)), None) if rate > 0 => RawFormat {
+                format: format,
+                rate: rate,
+                ..RawFormat::default()
+            },
+            (true, Some(Ok(rate)), Some(Ok(channels))) if rate > 0 && channels > 0 => {
+                RawFormat {
+                    format: format,
+                    rate: rate,
+                    channels: channels,
+                }
+            }
+            _ => {
+                warn!("Unable to parse raw output format, using: S16LE:44100:2");
+                RawFormat::default()
+            }
+        }
+    }
+
+    fn caps(&self) -> Option<gst::Caps> {
+        gst::Caps::from_string(&format!(
+            "audio/x-raw, format={}, rate={}, channels={}, layout=interleaved",
+            self.format, self.rate, self.channels
+        ))
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct AudioDevice {
     service: AudioService,
     device: Option<String>,
+    raw_format: RawFormat,
 }
 
 impl Default for AudioDevice {
@@ -30,6 +122,44 @@ impl Default for AudioDevice {
         AudioDevice {
             service: AudioService::Auto,
             device: None,
+            raw_format: RawFormat::default(),
+        }
+    }
+}
+
+impl AudioDevice {
+    pub fn is_stdout(&self) -> bool {
+        match self.service {
+            AudioService::Stdout => true,
+            _ => false,
+        }
+    }
+
+    /// Read the device from a file holding it as it would be given to --output.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let spec = fs::read_to_string(path)?;
+        match spec.trim() {
+            "" => Err(io::Error::new(io::ErrorKind::InvalidData, "No output device given")),
+            spec => Ok(AudioDevice::from(spec.split('#').collect::<Vec<&str>>())),
+        }
+    }
+
+    /// Make sure the output can be set up, so that mistakes are found before playing.
+    pub fn check(&self) -> Result<(), String> {
+        init()?;
+        match (self.service, &self.device) {
+            (AudioService::Gst, Some(description)) => parse_sink(description).map(|_| ()),
+            _ => make_sink(self)
+                .map(|_| ())
+                .ok_or_else(|| "GStreamer is missing the elements it needs".to_owned()),
+        }
+    }
+
+    // Encoders only finish writing a file when they see the end of the stream
+    fn is_encoded(&self) -> bool {
+        match (self.service, &self.device) {
+            (AudioService::File, Some(path)) => file_encoder(path).is_some(),
+            _ => false,
         }
     }
 }
@@ -52,6 +182,7 @@ impl<'a> From<Vec<&'a str>> for AudioDevice {
                 AudioDevice {
                     service: AudioService::Alsa,
                     device: device,
+                    ..AudioDevice::default()
                 }
             }
             "pulse" => {
@@ -63,8 +194,25 @@ impl<'a> From<Vec<&'a str>> for AudioDevice {
                 AudioDevice {
                     service: AudioService::Pulse,
                     device: device,
+                    ..AudioDevice::default()
                 }
             }
+            "file" if v.len() > 1 => AudioDevice {
+                service: AudioService::File,
+                device: Some(v[1].to_owned()),
+                raw_format: RawFormat::parse(v.get(2)),
+            },
+            // Put back anything in the description that was taken to separate the fields
+            "gst" if v.len() > 1 => AudioDevice {
+                service: AudioService::Gst,
+                device: Some(v[1..].join("#")),
+                ..AudioDevice::default()
+            },
+            "stdout" => AudioDevice {
+                service: AudioService::Stdout,
+                device: None,
+                raw_format: RawFormat::parse(v.get(1)),
+            },
             _ => {
                 warn!("Unable to parse output device, falling back to auto output selection");
                 AudioDevice::default()
@@ -73,6 +221,20 @@ impl<'a> From<Vec<&'a str>> for AudioDevice {
     }
 }
 
+#[derive(Clone, Copy, Default)]
+pub struct DeviceRelease {
+    pub power_off: bool,
+    pub idle_timeout: Option<time::Duration>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TrackInfo {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+}
+
+#[derive(Clone, Debug)]
 pub enum PlayerControl {
     Gain(f64, f64),
     Enable(bool),
@@ -84,12 +246,23 @@ pub enum PlayerControl {
         server_port: u16,
         server_ip: Ipv4Addr,
         control_ip: Ipv4Addr,
+        use_ssl: bool,
         http_headers: String,
     },
     Stop,
     Pause(bool),
     Unpause(bool),
+    // Milliseconds of the backend's clock
+    UnpauseAt {
+        at: u64,
+        quiet: bool,
+    },
     Skip(u32),
+    Visualiser {
+        level: bool,
+        bands: Option<u32>,
+    },
+    Output(AudioDevice),
 }
 
 impl actix::Message for PlayerControl {
@@ -103,39 +276,111 @@ pub enum PlayerMessages {
     Eos,
     Established,
     Headers(u8),
-    Error,
+    Error(PlayerError),
     Start,
     Streamdata {
         position: u64,
+        position_time: u64,
+        playing: bool,
         fullness: u32,
         output_buffer_fullness: u32,
     },
     Bufsize(usize),
     Sendstatus,
     Overrun,
+    OutputUnderrun,
+    InputUnderrun,
+    Metadata(TrackInfo),
+    Levels(Vec<f64>),
+    Spectrum(Vec<f32>),
+    OutputChanged(AudioDevice),
+    OutputUnchanged,
 }
 
 impl actix::Message for PlayerMessages {
     type Result = ();
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlayerError {
+    NotFound,
+    Network,
+    DecoderNotFound,
+    Decode,
+    Output,
+}
+
+impl PlayerError {
+    fn from_stream(error: &glib::Error, origin: &str) -> Self {
+        if let Some(gst::ResourceError::NotFound) = error.kind::<gst::ResourceError>() {
+            PlayerError::NotFound
+        } else if error.kind::<gst::ResourceError>().is_some() || origin == "source" {
+            PlayerError::Network
+        } else {
+            match error.kind::<gst::StreamError>() {
+                Some(gst::StreamError::CodecNotFound)
+                | Some(gst::StreamError::TypeNotFound)
+                | Some(gst::StreamError::WrongType) => PlayerError::DecoderNotFound,
+                _ => match error.kind::<gst::CoreError>() {
+                    Some(gst::CoreError::MissingPlugin) => PlayerError::DecoderNotFound,
+                    _ => PlayerError::Decode,
+                },
+            }
+        }
+    }
+
+    // The code sent in the error code field of the STMn that reports this error. The protocol
+    // leaves the values to the player and the server only logs them, so these are our own,
+    // numbered from 1 as 0 means no error
+    pub fn error_code(&self) -> u16 {
+        match *self {
+            PlayerError::NotFound => 1,
+            PlayerError::Network => 2,
+            PlayerError::DecoderNotFound => 3,
+            PlayerError::Decode => 4,
+            PlayerError::Output => 5,
+        }
+    }
+}
+
+impl fmt::Display for PlayerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PlayerError::NotFound => write!(f, "Stream not found"),
+            PlayerError::Network => write!(f, "Network error"),
+            PlayerError::DecoderNotFound => write!(f, "No decoder for stream"),
+            PlayerError::Decode => write!(f, "Decoding error"),
+            PlayerError::Output => write!(f, "Output device error"),
+        }
+    }
+}
+
 pub struct Player {
     gain: f64,
     enable: bool,
     output_device: AudioDevice,
     thread: Option<thread_control::Control>,
-    pub proto: actix::Addr<proto::Proto>,
+    pub proto: actix::Recipient<PlayerMessages>,
     pipeline: gst::Pipeline,
     count: u32,
+    tracks: Arc<Mutex<VecDeque<u64>>>,
+    device_release: DeviceRelease,
+    released: bool,
+    // The block in front of the sink, and whether audio has reached it
+    parked: Option<(gst::PadProbeId, Arc<AtomicBool>)>,
+    // A device to switch to once the sink lets go of the audio it paused with
+    pending_output: Option<AudioDevice>,
+    // Set once the end of the stream has got through the sink
+    ended: Arc<AtomicBool>,
+    idle_timer: Option<actix::SpawnHandle>,
 }
 
 impl Player {
-    pub fn new(proto: actix::Addr<proto::Proto>, output_device: AudioDevice) -> Self {
-        if let Err(e) = gst::init() {
-            error!("Unable to initialise GStreamer: {}", e);
-            ::std::process::exit(1);
-        }
-
+    pub fn new(
+        proto: actix::Recipient<PlayerMessages>,
+        output_device: AudioDevice,
+        device_release: DeviceRelease,
+    ) -> Self {
         Player {
             gain: 1.0,
             enable: false,
@@ -144,78 +389,455 @@ impl Player {
             proto: proto,
             pipeline: gst::Pipeline::new(Some("stormpipe")),
             count: 0,
+            tracks: Arc::new(Mutex::new(VecDeque::new())),
+            device_release: device_release,
+            released: false,
+            parked: None,
+            pending_output: None,
+            ended: Arc::new(AtomicBool::new(false)),
+            idle_timer: None,
         }
     }
-}
 
-impl actix::Actor for Player {
-    type Context = actix::Context<Self>;
+    // Hold the audio back in front of the sink, so that the sink can be closed or changed with
+    // nothing being pushed to it. Closing a sink that audio is waiting in makes everything
+    // upstream stop and throw away what it has queued. Audio that is playing soon reaches the
+    // block, but a sink that has paused keeps hold of what it paused with and cannot be parked
+    fn park(&mut self) -> bool {
+        if !self.block_audio() {
+            return false;
+        }
 
-    fn started(&mut self, _ctx: &mut actix::Context<Self>) {
-        if self.pipeline.get_by_name("sink").is_some() {
+        let deadline = time::Instant::now() + time::Duration::from_millis(PARK_TIMEOUT);
+        let parked = loop {
+            if let Some((_, ref blocked)) = self.parked {
+                if blocked.load(Ordering::SeqCst) {
+                    break true;
+                }
+            }
+            match self.pipeline.get_state(gst::ClockTime::from_mseconds(0)) {
+                (Ok(gst::StateChangeSuccess::Success), gst::State::Paused, _) => break false,
+                (_, gst::State::Playing, _) | (_, _, gst::State::Playing)
+                    if time::Instant::now() < deadline =>
+                {
+                    thread::sleep(time::Duration::from_millis(5))
+                }
+                // Nothing is in the sink when it is waiting for audio or not running at all
+                _ => break true,
+            }
+        };
+
+        if !parked {
+            self.unpark();
+        }
+        parked
+    }
+
+    // Put the block in front of the sink, without waiting for audio to reach it
+    fn block_audio(&mut self) -> bool {
+        if self.parked.is_some() {
+            return true;
+        }
+
+        let pad = match self
+            .pipeline
+            .get_by_name("resampler")
+            .and_then(|resampler| resampler.get_static_pad("src"))
+        {
+            Some(pad) => pad,
+            None => return false,
+        };
+        let blocked = Arc::new(AtomicBool::new(false));
+        let reached = blocked.clone();
+        let id = pad.add_probe(gst::PadProbeType::BLOCK_DOWNSTREAM, move |_, _| {
+            reached.store(true, Ordering::SeqCst);
+            gst::PadProbeReturn::Ok
+        });
+        match id {
+            Some(id) => {
+                self.parked = Some((id, blocked));
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Let the audio carry on to the sink, which takes it as it would at the start of a stream
+    fn unpark(&mut self) {
+        if let Some((id, _)) = self.parked.take() {
+            if let Some(pad) = self
+                .pipeline
+                .get_by_name("resampler")
+                .and_then(|resampler| resampler.get_static_pad("src"))
+            {
+                pad.remove_probe(id);
+            }
+        }
+    }
+
+    // Encoders only write out the end of a file, such as the length in a wav header, when they
+    // see the end of the stream. A paused sink holds on to the audio it paused with, which would
+    // keep the end out, so it is let go of once nothing more can follow it
+    fn finish_encoding(&mut self) {
+        let (_, state, pending) = self.pipeline.get_state(gst::ClockTime::from_mseconds(0));
+        let running = [state, pending]
+            .iter()
+            .any(|state| *state == gst::State::Paused || *state == gst::State::Playing);
+        if self.released || !running {
             return;
         }
 
-        // Audio Sink
-        let sink = match self.output_device.service {
-            AudioService::Auto => gst::ElementFactory::make("autoaudiosink", Some("sink")),
-            AudioService::Alsa => gst::ElementFactory::make("alsasink", Some("sink")),
-            AudioService::Pulse => gst::ElementFactory::make("pulsesink", Some("sink")),
+        let pad = match self
+            .pipeline
+            .get_by_name("sink")
+            .and_then(|sink| sink.get_static_pad("sink"))
+        {
+            Some(pad) => pad,
+            None => return,
+        };
+        if !self.block_audio() {
+            return;
         }
-        .unwrap();
 
-        if let Some(ref device) = self.output_device.device {
-            sink.set_property("device", &device).unwrap();
+        info!("Finishing the output file");
+        self.ended.store(false, Ordering::SeqCst);
+        let _ = self.pipeline.set_state(gst::State::Playing);
+        pad.send_event(gst::Event::new_eos().build());
+
+        let deadline = time::Instant::now() + time::Duration::from_millis(FINISH_TIMEOUT);
+        while !self.ended.load(Ordering::SeqCst) {
+            if time::Instant::now() >= deadline {
+                warn!("Output file may be incomplete, the end of the stream did not get through");
+                break;
+            }
+            thread::sleep(time::Duration::from_millis(5));
+        }
+    }
+
+    // Close the audio device whilst paused, leaving the rest of the pipeline as it is
+    fn release_device(&mut self) {
+        let (_, state, pending) = self.pipeline.get_state(gst::ClockTime::from_mseconds(0));
+        if self.released || (state != gst::State::Paused && pending != gst::State::Paused) {
+            return;
+        }
+
+        if !self.park() {
+            info!("Keeping the audio device open as it is holding on to audio");
+            return;
+        }
+
+        if let Some(sink) = self.pipeline.get_by_name("sink") {
+            info!("Releasing audio device");
+            sink.set_locked_state(true);
+            let _ = sink.set_state(gst::State::Null);
+            self.released = true;
+        }
+    }
+
+    fn reopen_device(&mut self) {
+        if self.released {
+            self.released = false;
+            if let Some(sink) = self.pipeline.get_by_name("sink") {
+                info!("Reopening audio device");
+                sink.set_locked_state(false);
+                let _ = sink.sync_state_with_parent();
+            }
+        }
+        self.unpark();
+    }
+
+    fn pause(&mut self, quiet: bool, ctx: &mut actix::Context<Self>) {
+        info!("Pausing stream");
+        // Only a pause that may go on for a while is worth letting go of the device for
+        let release = self.device_release.power_off || self.device_release.idle_timeout.is_some();
+        if release && !quiet {
+            self.park();
+        }
+
+        // A timed start leaves the pipeline not tracking its running time, so put that back
+        if self.pipeline.get_start_time().nseconds().is_none() {
+            self.pipeline
+                .set_start_time(gst::ClockTime::from_nseconds(0));
+        }
+        if !self.pipeline.set_state(gst::State::Paused).is_err() {
+            if !quiet {
+                let _ = self.proto.do_send(PlayerMessages::Paused);
+            }
+        }
+
+        if let (Some(timeout), false) = (self.device_release.idle_timeout, quiet) {
+            self.cancel_idle_timer(ctx);
+            self.idle_timer = Some(ctx.run_later(timeout, |player, _| {
+                player.idle_timer = None;
+                player.release_device();
+            }));
+        }
+    }
+
+    // Make the sink for an output device, ready to be added to the pipeline
+    fn new_sink(&self, output_device: &AudioDevice, replacing: bool) -> Option<gst::Element> {
+        let sink = match make_sink(output_device) {
+            Some(sink) => sink,
+            None => {
+                error!("Unable to set up the output device");
+                return None;
+            }
         };
 
         {
-            let service = match self.output_device.service {
+            let service = match output_device.service {
                 AudioService::Alsa => "ALSA",
                 AudioService::Pulse => "PULSEAUDIO",
+                AudioService::File => "FILE",
+                AudioService::Stdout => "STDOUT",
+                AudioService::Gst => "GSTREAMER",
                 _ => "AUTO",
             };
-            let device = if let Ok(prop) = sink.get_property("device-name") {
-                prop.get().unwrap_or("default".to_owned())
-            } else {
-                "default".to_owned()
-            };
+            let device = match sink.get_property("device-name") {
+                Ok(prop) => prop.get(),
+                Err(_) => None,
+            }
+            .or_else(|| output_device.device.clone())
+            .unwrap_or("default".to_owned());
 
             info!("Using audio service: {} with device: {}", service, device);
         }
 
-        if self.pipeline.add(&sink).is_err() {
-            return;
-        };
+        // Note the running time at which each track's first sample reaches the output. A
+        // replacement sink is sent the stream start again, which is not a new track
+        let tracks = self.tracks.clone();
+        let segment = Mutex::new(gst::FormattedSegment::<gst::ClockTime>::new());
+        let new_track = AtomicBool::new(false);
+        let replayed = AtomicBool::new(replacing);
+        let sink_pad = sink.get_static_pad("sink")?;
+        sink_pad.add_probe(
+            gst::PadProbeType::BUFFER | gst::PadProbeType::EVENT_DOWNSTREAM,
+            move |_, probe_info| {
+                match probe_info.data {
+                    Some(gst::PadProbeData::Event(ref event)) => match event.view() {
+                        gst::EventView::StreamStart(..) => {
+                            if !replayed.load(Ordering::SeqCst) {
+                                new_track.store(true, Ordering::SeqCst);
+                            }
+                        }
+                        gst::EventView::Segment(seg) => {
+                            if let Some(seg) = seg.get_segment().downcast_ref::<gst::ClockTime>() {
+                                let mut segment = segment.lock().unwrap();
+                                // A skip sends the track's segment again, moved earlier, and
+                                // what is heard of the track jumps ahead by as much
+                                if !new_track.load(Ordering::SeqCst)
+                                    && !replayed.load(Ordering::SeqCst)
+                                {
+                                    let skipped = skipped_time(&segment, seg);
+                                    if let Some(start) = tracks.lock().unwrap().back_mut() {
+                                        *start = start.saturating_sub(skipped);
+                                    }
+                                }
+                                *segment = seg.clone();
+                            }
+                        }
+                        _ => (),
+                    },
+                    Some(gst::PadProbeData::Buffer(ref buffer)) => {
+                        replayed.store(false, Ordering::SeqCst);
+                        if new_track.swap(false, Ordering::SeqCst) {
+                            let running = segment.lock().unwrap().to_running_time(buffer.get_pts());
+                            if let Some(running) = running.nseconds() {
+                                tracks.lock().unwrap().push_back(running);
+                            }
+                        }
+                    }
+                    _ => (),
+                }
+                gst::PadProbeReturn::Ok
+            },
+        );
 
-        // Audio Resample
-        let resampler = gst::ElementFactory::make("audioresample", Some("resampler")).unwrap();
-        if self.pipeline.add(&resampler).is_err() {
-            return;
-        };
+        Some(sink)
+    }
 
-        if resampler.link(&sink).is_err() {
+    // Swap the sink with the audio parked in front of it, so what is queued up carries on
+    // through the new one. The server connection is told whether the new device is in use
+    fn replace_sink(&mut self, output_device: AudioDevice) {
+        let was_parked = self.parked.is_some();
+        if !self.park() {
+            info!("Switching output device when playback resumes");
+            self.pending_output = Some(output_device);
             return;
-        };
+        }
+        self.pending_output = None;
 
-        // Volume
-        let volume = gst::ElementFactory::make("volume", Some("volume")).unwrap();
-        if self.pipeline.add(&volume).is_err() {
-            return;
+        let replaced = self.swap_sink(&output_device);
+        // A released device keeps the audio parked until it is reopened
+        if !was_parked {
+            self.unpark();
+        }
+
+        if replaced {
+            let _ = self.proto.do_send(PlayerMessages::OutputChanged(output_device));
+        } else {
+            let _ = self.proto.do_send(PlayerMessages::OutputUnchanged);
+        }
+    }
+
+    // The old sink is only closed once the new one is in its place, otherwise it is put back
+    fn swap_sink(&mut self, output_device: &AudioDevice) -> bool {
+        let resampler = match self.pipeline.get_by_name("resampler") {
+            Some(resampler) => resampler,
+            None => return false,
+        };
+        let sink = match self.new_sink(output_device, true) {
+            Some(sink) => sink,
+            None => return false,
         };
 
-        if volume.link(&resampler).is_err() {
-            return;
+        // Sinks go by the same name, so the old one has to leave before the new one comes in
+        let old_sink = self.pipeline.get_by_name("sink");
+        if let Some(ref old_sink) = old_sink {
+            resampler.unlink(old_sink);
+            let _ = self.pipeline.remove(old_sink);
+        }
+
+        if self.pipeline.add(&sink).is_err() || resampler.link(&sink).is_err() {
+            error!("Unable to link the new output device");
+            let _ = self.pipeline.remove(&sink);
+            if let Some(ref old_sink) = old_sink {
+                let _ = self.pipeline.add(old_sink);
+                let _ = resampler.link(old_sink);
+            }
+            return false;
+        }
+
+        if let Some(old_sink) = old_sink {
+            let _ = old_sink.set_state(gst::State::Null);
+        }
+        // A released device stays closed until it is next needed
+        if self.released {
+            sink.set_locked_state(true);
+        } else {
+            let _ = sink.sync_state_with_parent();
+        }
+
+        self.output_device = output_device.clone();
+        true
+    }
+
+    fn switch_pending_output(&mut self) {
+        if let Some(output_device) = self.pending_output.take() {
+            self.replace_sink(output_device);
+        }
+    }
+
+    // The taps cost something even when they say nothing, the spectrum especially, so they are
+    // only in the pipeline whilst a visualiser is wanted. They are swapped between buffers
+    fn set_visualiser(&self, level: bool, bands: Option<u32>) {
+        let (volume, balance) = match (
+            self.pipeline.get_by_name("volume"),
+            self.pipeline.get_by_name("balance"),
+        ) {
+            (Some(volume), Some(balance)) => (volume, balance),
+            _ => return,
+        };
+        let pad = match volume.get_static_pad("src") {
+            Some(pad) => pad,
+            None => return,
         };
 
+        let pipeline = self.pipeline.clone();
+        pad.add_probe(gst::PadProbeType::IDLE, move |_, _| {
+            // Removing the old taps unlinks them too
+            for name in &["level", "spectrum"] {
+                if let Some(tap) = pipeline.get_by_name(name) {
+                    let _ = tap.set_state(gst::State::Null);
+                    let _ = pipeline.remove(&tap);
+                }
+            }
+            volume.unlink(&balance);
+
+            let mut taps = Vec::new();
+            if level {
+                let level = gst::ElementFactory::make("level", Some("level")).unwrap();
+                level.set_property("interval", &VISUALISER_INTERVAL).unwrap();
+                taps.push(level);
+            }
+            if let Some(bands) = bands {
+                let spectrum = gst::ElementFactory::make("spectrum", Some("spectrum")).unwrap();
+                spectrum.set_property("interval", &VISUALISER_INTERVAL).unwrap();
+                spectrum.set_property("threshold", &VISUALISER_FLOOR).unwrap();
+                spectrum.set_property("bands", &bands).unwrap();
+                taps.push(spectrum);
+            }
+
+            let mut chain = vec![&volume];
+            chain.extend(taps.iter());
+            chain.push(&balance);
+            let linked = pipeline.add_many(&taps.iter().collect::<Vec<_>>()).is_ok()
+                && gst::Element::link_many(&chain).is_ok();
+            if linked {
+                for tap in &taps {
+                    let _ = tap.sync_state_with_parent();
+                }
+            } else {
+                error!("Unable to add the visualiser taps");
+                for tap in &taps {
+                    let _ = pipeline.remove(tap);
+                }
+                let _ = volume.link(&balance);
+            }
+            gst::PadProbeReturn::Remove
+        });
+    }
+
+    fn cancel_idle_timer(&mut self, ctx: &mut actix::Context<Self>) {
+        if let Some(handle) = self.idle_timer.take() {
+            ctx.cancel_future(handle);
+        }
+    }
+
+    // Everything from the concat that streams are added to through to the sink
+    fn build_pipeline(&mut self) -> Option<()> {
+        // Audio Sink
+        let sink = self.new_sink(&self.output_device, false)?;
+        self.pipeline.add(&sink).ok()?;
+
+        // Audio Resample
+        let resampler = gst::ElementFactory::make("audioresample", Some("resampler"))?;
+        self.pipeline.add(&resampler).ok()?;
+
+        resampler.link(&sink).ok()?;
+
+        // Balance, which needs its own converter as it only handles a couple of sample formats
+        let balance_converter =
+            gst::ElementFactory::make("audioconvert", Some("balance_converter"))?;
+        self.pipeline.add(&balance_converter).ok()?;
+
+        balance_converter.link(&resampler).ok()?;
+
+        let balance = gst::ElementFactory::make("audiopanorama", Some("balance"))?;
+        // simple method, so the louder channel is left alone
+        balance.set_property_from_str("method", "simple");
+        self.pipeline.add(&balance).ok()?;
+
+        balance.link(&balance_converter).ok()?;
+
+        // Volume, the visualiser taps go in after it when the server asks for a visualiser
+        let volume = gst::ElementFactory::make("volume", Some("volume"))?;
+        self.pipeline.add(&volume).ok()?;
+
+        volume.link(&balance).ok()?;
+
         // Output Buffer
-        let obuf = gst::ElementFactory::make("queue", Some("obuf")).unwrap();
-        if self.pipeline.add(&obuf).is_err() {
-            return;
-        };
+        let obuf = gst::ElementFactory::make("queue", Some("obuf"))?;
+        self.pipeline.add(&obuf).ok()?;
 
-        if obuf.link(&volume).is_err() {
-            return;
-        };
+        obuf.link(&volume).ok()?;
+
+        let proto = self.proto.clone();
+        obuf.connect("underrun", true, move |_| {
+            let _ = proto.do_send(PlayerMessages::OutputUnderrun);
+            None
+        })
+        .unwrap();
 
         let proto = self.proto.clone();
         let obuf_src = obuf.get_static_pad("src").unwrap();
@@ -223,7 +845,7 @@ impl actix::Actor for Player {
             if let Some(ref probe_data) = probe_info.data {
                 if let gst::PadProbeData::Event(event) = probe_data {
                     if event.get_type() == gst::EventType::StreamStart {
-                        proto.do_send(PlayerMessages::Start);
+                        let _ = proto.do_send(PlayerMessages::Start);
                     }
                 }
             }
@@ -231,25 +853,39 @@ impl actix::Actor for Player {
         });
 
         // Audio Converter
-        let converter = gst::ElementFactory::make("audioconvert", Some("converter")).unwrap();
-        if self.pipeline.add(&converter).is_err() {
-            return;
-        };
+        let converter = gst::ElementFactory::make("audioconvert", Some("converter"))?;
+        self.pipeline.add(&converter).ok()?;
 
-        if converter.link(&obuf).is_err() {
-            return;
-        };
+        converter.link(&obuf).ok()?;
 
         // Concat
-        let concat = gst::ElementFactory::make("concat", Some("concat")).unwrap();
+        let concat = gst::ElementFactory::make("concat", Some("concat"))?;
         // let player = ctx.address().clone();
-        if self.pipeline.add(&concat).is_err() {
+        self.pipeline.add(&concat).ok()?;
+
+        concat.link(&converter).ok()?;
+
+        Some(())
+    }
+}
+
+impl actix::Actor for Player {
+    type Context = actix::Context<Self>;
+
+    fn started(&mut self, ctx: &mut actix::Context<Self>) {
+        if self.pipeline.get_by_name("sink").is_some() {
             return;
-        };
+        }
+
+        // Jiffies come from this clock too, so the server's timings line up with the audio
+        self.pipeline.use_clock(Some(&clock()));
 
-        if concat.link(&converter).is_err() {
+        if self.build_pipeline().is_none() {
+            error!("Unable to set up the pipeline for the output");
+            let _ = self.proto.do_send(PlayerMessages::Error(PlayerError::Output));
+            ctx.stop();
             return;
-        };
+        }
 
         //Set up periodic message
         let proto = self.proto.clone();
@@ -257,6 +893,8 @@ impl actix::Actor for Player {
         self.thread = Some(control);
         let bus = self.pipeline.get_bus().unwrap();
         let pipeline_weak = self.pipeline.downgrade();
+        let tracks = self.tracks.clone();
+        let ended = self.ended.clone();
         ::std::thread::spawn(move || {
             let pipeline = match pipeline_weak.upgrade() {
                 Some(pipeline) => pipeline,
@@ -273,43 +911,109 @@ impl actix::Actor for Player {
                 match msg {
                     Some(msg) => match msg.view() {
                         MessageView::Error(error) => {
-                            error!(
-                                "Stream error: {}",
-                                if let Some(e) = error.get_debug() {
-                                    e
-                                } else {
-                                    "undefined".to_owned()
-                                }
-                            );
+                            let origin = error
+                                .get_src()
+                                .map(|src| src.get_name())
+                                .unwrap_or("unknown".to_owned());
 
+                            // The element of ours the error came from, or whatever is at the
+                            // top if it is not in the pipeline at all
                             if let Some(mut source) = error.get_src() {
                                 while !source.has_as_parent(&pipeline) {
-                                    source = source.get_parent().unwrap();
+                                    source = match source.get_parent() {
+                                        Some(parent) => parent,
+                                        None => break,
+                                    };
                                 }
 
+                                let player_error = if source.get_name().starts_with("storm_") {
+                                    PlayerError::from_stream(&error.get_error(), &origin)
+                                } else {
+                                    PlayerError::Output
+                                };
+
+                                error!(
+                                    "{} from {}: {} ({})",
+                                    player_error,
+                                    origin,
+                                    error.get_error(),
+                                    error.get_debug().unwrap_or("undefined".to_owned())
+                                );
+
                                 if source.get_name().starts_with("storm_") {
                                     if let Ok(bin) = source.dynamic_cast::<gst::Bin>() {
                                         block(bin);
-                                        proto.do_send(PlayerMessages::Error);
                                     }
                                 }
+                                let _ = proto.do_send(PlayerMessages::Error(player_error));
                             }
                         }
 
                         MessageView::Element(element) => {
-                            if let Some(source) = element.get_src() {
-                                if source.get_name() == "source" {
-                                    if let Some(structure) = element.get_structure() {
-                                        if structure.get_name() == "http-headers" {
-                                            proto.do_send(PlayerMessages::Established);
-                                            let crlf = structure.iter().count() as u8;
-                                            proto.do_send(PlayerMessages::Headers(crlf));
+                            if let (Some(source), Some(structure)) =
+                                (element.get_src(), element.get_structure())
+                            {
+                                match (source.get_name().as_str(), structure.get_name()) {
+                                    ("source", "http-headers") => {
+                                        let _ = proto.do_send(PlayerMessages::Established);
+                                        let crlf = structure.iter().count() as u8;
+                                        let _ = proto.do_send(PlayerMessages::Headers(crlf));
+                                    }
+
+                                    // Levels are in dB, one for each channel
+                                    ("level", "level") => {
+                                        if let Some(peaks) =
+                                            structure.get::<glib::ValueArray>("peak")
+                                        {
+                                            let _ = proto.do_send(PlayerMessages::Levels(
+                                                peaks
+                                                    .iter()
+                                                    .filter_map(|v| v.get::<f64>())
+                                                    .collect(),
+                                            ));
                                         }
                                     }
+
+                                    // Magnitudes are in dB, one for each band
+                                    ("spectrum", "spectrum") => {
+                                        if let Some(magnitudes) =
+                                            structure.get::<gst::List>("magnitude")
+                                        {
+                                            let _ = proto.do_send(PlayerMessages::Spectrum(
+                                                magnitudes
+                                                    .as_slice()
+                                                    .iter()
+                                                    .filter_map(|v| v.get::<f32>())
+                                                    .collect(),
+                                            ));
+                                        }
+                                    }
+
+                                    _ => (),
                                 }
                             }
                         }
 
+                        MessageView::Tag(tag) => {
+                            let tags = tag.get_tags();
+                            let track = TrackInfo {
+                                title: tags
+                                    .get::<gst::tags::Title>()
+                                    .and_then(|v| v.get().map(|s| s.to_owned())),
+                                artist: tags
+                                    .get::<gst::tags::Artist>()
+                                    .and_then(|v| v.get().map(|s| s.to_owned())),
+                                album: tags
+                                    .get::<gst::tags::Album>()
+                                    .and_then(|v| v.get().map(|s| s.to_owned())),
+                            };
+                            if track.title.is_some() || track.artist.is_some() {
+                                let _ = proto.do_send(PlayerMessages::Metadata(track));
+                            }
+                        }
+
+                        MessageView::Eos(..) => ended.store(true, Ordering::SeqCst),
+
                         MessageView::Latency(..) => {
                             info!("Recalculating latency");
                             let _ = pipeline.recalculate_latency();
@@ -339,7 +1043,7 @@ impl actix::Actor for Player {
                                                 None => None,
                                             };
                                             let _ = pipeline.remove(&bin);
-                                            proto.do_send(PlayerMessages::Flushed);
+                                            let _ = proto.do_send(PlayerMessages::Flushed);
                                             if let Some(sink_pad) = sink {
                                                 if let Some(concat) = sink_pad.get_parent_element()
                                                 {
@@ -357,8 +1061,11 @@ impl actix::Actor for Player {
 
                     None => {
                         let (ibuf_fullness, obuf_fullness) = buffer_fullness(&pipeline);
-                        proto.do_send(PlayerMessages::Streamdata {
-                            position: query_pos(&pipeline),
+                        let (_, state, _) = pipeline.get_state(gst::ClockTime::from_mseconds(0));
+                        let _ = proto.do_send(PlayerMessages::Streamdata {
+                            position: track_position(&pipeline, &tracks),
+                            position_time: clock().get_time().mseconds().unwrap_or(0),
+                            playing: state == gst::State::Playing,
                             fullness: ibuf_fullness,
                             output_buffer_fullness: obuf_fullness,
                         });
@@ -372,7 +1079,7 @@ impl actix::Actor for Player {
 impl actix::Handler<PlayerControl> for Player {
     type Result = ();
 
-    fn handle(&mut self, msg: PlayerControl, _ctx: &mut actix::Context<Self>) {
+    fn handle(&mut self, msg: PlayerControl, ctx: &mut actix::Context<Self>) {
         match msg {
             PlayerControl::Gain(gain_left, gain_right) => {
                 self.gain = if gain_left > gain_right {
@@ -385,6 +1092,18 @@ impl actix::Handler<PlayerControl> for Player {
                 if let Some(volume) = self.pipeline.get_by_name("volume") {
                     volume.set_property("volume", &self.gain).unwrap();
                 }
+
+                let panorama = if gain_left > gain_right {
+                    (gain_right / gain_left - 1.0) as f32
+                } else if gain_right > gain_left {
+                    (1.0 - gain_left / gain_right) as f32
+                } else {
+                    0.0
+                };
+                info!("Setting balance to {}", panorama);
+                if let Some(balance) = self.pipeline.get_by_name("balance") {
+                    balance.set_property("panorama", &panorama).unwrap();
+                }
             }
             
             PlayerControl::Enable(enable) => {
@@ -393,6 +1112,20 @@ impl actix::Handler<PlayerControl> for Player {
                 if let Some(volume) = self.pipeline.get_by_name("volume") {
                     volume.set_property("mute", &!enable).unwrap();
                 }
+
+                if self.device_release.power_off {
+                    if enable {
+                        self.reopen_device();
+                    } else {
+                        // The server is told, so that it does not think we are still playing
+                        let (_, state, _) =
+                            self.pipeline.get_state(gst::ClockTime::from_mseconds(0));
+                        if state == gst::State::Playing {
+                            self.pause(false, ctx);
+                        }
+                        self.release_device();
+                    }
+                }
             }
             
             PlayerControl::Stream {
@@ -403,9 +1136,12 @@ impl actix::Handler<PlayerControl> for Player {
                 server_port,
                 server_ip,
                 control_ip,
+                use_ssl,
                 http_headers,
             } => {
                 info!("Got stream request, autostart: {}", autostart);
+                self.cancel_idle_timer(ctx);
+                self.reopen_device();
                 
                 // wait for pipeline state to settle
                 while {
@@ -424,17 +1160,8 @@ impl actix::Handler<PlayerControl> for Player {
                     server_ip
                 };
 
-                let get = if let Some(line) = http_headers.lines().nth(0) {
-                    if let Some(get) = line.split_whitespace().nth(1) {
-                        get.to_owned()
-                    } else {
-                        String::new()
-                    }
-                } else {
-                    String::new()
-                };
-
-                let location = format!("http://{}:{}{}", server_ip, server_port, get);
+                let request = HttpRequest::from(http_headers.as_str());
+                let location = request.location(use_ssl, server_ip, server_port);
                 info!("{}", location);
 
                 let stream = gst::Bin::new(Some(format!("storm_{}", self.count).as_str()));
@@ -442,10 +1169,31 @@ impl actix::Handler<PlayerControl> for Player {
 
                 let source = gst::ElementFactory::make("souphttpsrc", Some("source")).unwrap();
                 source
-                    .set_property("user-agent", &"Storm".to_owned())
+                    .set_property(
+                        "user-agent",
+                        &request.user_agent.clone().unwrap_or("Storm".to_owned()),
+                    )
                     .unwrap();
                 source.set_property("location", &location).unwrap();
                 source.set_property("iradio-mode", &true).unwrap();
+                source.set_property("automatic-redirect", &true).unwrap();
+
+                if !request.headers.is_empty() {
+                    let mut extra_headers = gst::Structure::new_empty("extra-headers");
+                    for (name, values) in request.extra_headers() {
+                        debug!("Forwarding header {}: {}", name, values.join(", "));
+                        if values.len() == 1 {
+                            extra_headers.set(name.as_str(), &values[0]);
+                        } else {
+                            let values: Vec<&dyn glib::ToSendValue> =
+                                values.iter().map(|v| v as &dyn glib::ToSendValue).collect();
+                            extra_headers.set(name.as_str(), &gst::Array::new(&values));
+                        }
+                    }
+                    source
+                        .set_property("extra-headers", &extra_headers)
+                        .unwrap();
+                }
 
                 if let Some(obuf) = self.pipeline.get_by_name("obuf") {
                     obuf.set_property("max-size-time", &(&output_threshold))
@@ -474,7 +1222,7 @@ impl actix::Handler<PlayerControl> for Player {
                                 }
                                 _ => 0,
                             };
-                            proto.do_send(PlayerMessages::Bufsize(buf_size));
+                            let _ = proto.do_send(PlayerMessages::Bufsize(buf_size));
                             gst::PadProbeReturn::Ok
                         },
                     );
@@ -484,7 +1232,7 @@ impl actix::Handler<PlayerControl> for Player {
                         if let Some(ref probe_data) = probe_info.data {
                             if let gst::PadProbeData::Event(event) = probe_data {
                                 if event.get_type() == gst::EventType::Eos {
-                                    proto.do_send(PlayerMessages::Eos);
+                                    let _ = proto.do_send(PlayerMessages::Eos);
                                 }
                             }
                         }
@@ -495,7 +1243,13 @@ impl actix::Handler<PlayerControl> for Player {
                 ibuf.set_property("max-size-bytes", &threshold).unwrap();
                 let proto = self.proto.clone();
                 ibuf.connect("overrun", true, move |_| {
-                    proto.do_send(PlayerMessages::Overrun);
+                    let _ = proto.do_send(PlayerMessages::Overrun);
+                    None
+                })
+                .unwrap();
+                let proto = self.proto.clone();
+                ibuf.connect("underrun", true, move |_| {
+                    let _ = proto.do_send(PlayerMessages::InputUnderrun);
                     None
                 })
                 .unwrap();
@@ -543,66 +1297,733 @@ impl actix::Handler<PlayerControl> for Player {
                 let _ = self.pipeline.add(&stream);
                 let _ = stream.sync_state_with_parent();
 
-                info!("Starting the stream");
-                let _ = self.pipeline.set_state(gst::State::Playing);
+                // Without autostart the server says when to start, unless we are already playing
+                let (_, state, _) = self.pipeline.get_state(gst::ClockTime::from_mseconds(0));
+                if autostart || state == gst::State::Playing {
+                    info!("Starting the stream");
+                    let _ = self.pipeline.set_state(gst::State::Playing);
+                } else {
+                    info!("Buffering the stream");
+                    let _ = self.pipeline.set_state(gst::State::Paused);
+                }
             }
 
             PlayerControl::Stop => {
                 info!("Stopping stream");
+                self.cancel_idle_timer(ctx);
+                if self.output_device.is_encoded() {
+                    self.finish_encoding();
+                }
+                if self.released {
+                    if let Some(sink) = self.pipeline.get_by_name("sink") {
+                        sink.set_locked_state(false);
+                    }
+                    self.released = false;
+                }
                 let _ = self.pipeline.set_state(gst::State::Null);
+                self.unpark();
+                self.pipeline.set_start_time(gst::ClockTime::from_nseconds(0));
+                self.tracks.lock().unwrap().clear();
+                if let Some(pad) = self
+                    .pipeline
+                    .get_by_name("obuf")
+                    .and_then(|obuf| obuf.get_static_pad("src"))
+                {
+                    pad.set_offset(0);
+                }
+                self.switch_pending_output();
             }
 
-            PlayerControl::Pause(quiet) => {
-                info!("Pausing stream");
-                if !self.pipeline.set_state(gst::State::Paused).is_err() {
+            PlayerControl::Pause(quiet) => self.pause(quiet, ctx),
+
+            PlayerControl::Unpause(quiet) => {
+                info!("Resuming stream");
+                self.cancel_idle_timer(ctx);
+                self.reopen_device();
+                if !self.pipeline.set_state(gst::State::Playing).is_err() {
                     if !quiet {
-                        self.proto.do_send(PlayerMessages::Paused);
+                        let _ = self.proto.do_send(PlayerMessages::Unpaused);
                     }
                 }
+                self.switch_pending_output();
             }
 
-            PlayerControl::Unpause(quiet) => {
-                info!("Resuming stream");
+            PlayerControl::UnpauseAt { at, quiet } => {
+                self.cancel_idle_timer(ctx);
+                self.reopen_device();
+                let (_, state, pending) =
+                    self.pipeline.get_state(gst::ClockTime::from_mseconds(0));
+                if state != gst::State::Playing || pending == gst::State::Paused {
+                    // Move the base time so the paused position is heard exactly at the given time
+                    let running = self.pipeline.get_start_time().nseconds().unwrap_or(0);
+                    let latency = query_latency(&self.pipeline);
+                    let base_time = (at * 1_000_000)
+                        .saturating_sub(running)
+                        .saturating_sub(latency);
+                    info!(
+                        "Resuming stream at: {}ms, latency: {}ms",
+                        at,
+                        latency / 1_000_000
+                    );
+                    self.pipeline.set_start_time(gst::ClockTime::none());
+                    self.pipeline
+                        .set_base_time(gst::ClockTime::from_nseconds(base_time));
+                }
+
                 if !self.pipeline.set_state(gst::State::Playing).is_err() {
                     if !quiet {
-                        self.proto.do_send(PlayerMessages::Unpaused);
+                        let _ = self.proto.do_send(PlayerMessages::Unpaused);
                     }
                 }
+                self.switch_pending_output();
             }
 
             PlayerControl::Skip(interval) => {
-                let newpos = query_pos(&self.pipeline) + interval as u64;
-                info!("Skipping to postition: {}", newpos);
-                let flags = {
-                    let (_, state, _) = self.pipeline.get_state(gst::ClockTime::none());
-                    if state == gst::State::Playing {
-                        gst::SeekFlags::SKIP
-                    } else {
-                        gst::SeekFlags::SKIP | gst::SeekFlags::FLUSH
-                    }
-                };
-                let seek = gst::Event::new_seek(
-                    1.0,
-                    flags,
-                    gst::SeekType::Set,
-                    gst::ClockTime::from_mseconds(newpos),
-                    gst::SeekType::None,
-                    gst::ClockTime::none(),
-                )
-                .build();
-                self.pipeline.send_event(seek);
+                // Bring the audio forward in time so the sink skips what is now late. The offset
+                // carries on into later tracks, whose start times are noted with it already
+                // applied, so only the track being skipped needs to account for it
+                info!("Skipping ahead by: {}ms", interval);
+                if let Some(pad) = self
+                    .pipeline
+                    .get_by_name("obuf")
+                    .and_then(|obuf| obuf.get_static_pad("src"))
+                {
+                    let offset = pad.get_offset() - interval as i64 * 1_000_000;
+                    pad.set_offset(offset);
+                }
+            }
+
+            PlayerControl::Output(output_device) => {
+                info!("Switching output device");
+                self.replace_sink(output_device);
+            }
+
+            PlayerControl::Visualiser { level, bands } => {
+                info!(
+                    "Setting visualiser levels: {}, spectrum bands: {}",
+                    level,
+                    bands.unwrap_or(0)
+                );
+                self.set_visualiser(level, bands);
             }
         }
     }
 }
 
-fn query_pos(pipeline: &gst::Pipeline) -> u64 {
-    let mut q = gst::Query::new_position(gst::Format::Time);
-    if pipeline.query(&mut q) {
-        match q.get_result() {
-            gst::GenericFormattedValue::Time(pos) => pos.mseconds().unwrap_or(0),
-            _ => 0,
+/// GStreamer has to be set up before a player is made.
+pub fn init() -> Result<(), String> {
+    gst::init().map_err(|e| format!("Unable to initialise GStreamer: {}", e))
+}
+
+pub fn clock() -> gst::Clock {
+    gst::SystemClock::obtain()
+}
+
+fn make_sink(output_device: &AudioDevice) -> Option<gst::Element> {
+    let sink = match output_device.service {
+        AudioService::Auto => gst::ElementFactory::make("autoaudiosink", Some("sink")),
+        AudioService::Alsa => gst::ElementFactory::make("alsasink", Some("sink")),
+        AudioService::Pulse => gst::ElementFactory::make("pulsesink", Some("sink")),
+        AudioService::File => {
+            let path = output_device.device.as_ref()?;
+            return make_file_sink(path, &output_device.raw_format);
+        }
+        AudioService::Stdout => {
+            let sink = gst::ElementFactory::make("fdsink", None)?;
+            sink.set_property("fd", &1i32).ok()?;
+            return make_raw_sink(sink, &output_device.raw_format);
+        }
+        AudioService::Gst => {
+            let description = output_device.device.as_ref()?;
+            return parse_sink(description)
+                .map_err(|e| error!("Unable to use output pipeline: {}", e))
+                .ok();
+        }
+    };
+
+    if let (Some(sink), Some(device)) = (&sink, &output_device.device) {
+        sink.set_property("device", device).unwrap();
+    };
+
+    sink
+}
+
+// Format codes of the streams there are decoders for
+pub fn decode_caps() -> Vec<String> {
+    // Each format needs something in the registry that will accept every one of these caps
+    let decoders: &[(&str, &[&str])] = &[
+        ("alc", &["video/quicktime", "audio/x-alac"]),
+        ("wma", &["video/x-ms-asf", "audio/x-wma, wmaversion=(int)2"]),
+        ("wmap", &["video/x-ms-asf", "audio/x-wma, wmaversion=(int)3"]),
+        ("wmal", &["video/x-ms-asf", "audio/x-wma, wmaversion=(int)4"]),
+        ("ogg", &["application/ogg", "audio/x-vorbis"]),
+        ("ops", &["application/ogg", "audio/x-opus"]),
+        ("flc", &["audio/x-flac"]),
+        ("aac", &["audio/mpeg, mpegversion=(int)4"]),
+        ("pcm", &["audio/x-wav", "audio/x-aiff"]),
+        ("mp3", &["audio/mpeg, mpegversion=(int)1, layer=(int)3"]),
+        ("dsf", &["audio/x-dsf"]),
+        ("dff", &["audio/x-dff"]),
+    ];
+
+    let factories = gst::ElementFactory::list_get_elements(
+        gst::ElementFactoryListType::DECODER
+            | gst::ElementFactoryListType::DEMUXER
+            | gst::ElementFactoryListType::PARSER,
+        gst::Rank::Marginal,
+    );
+
+    let caps: Vec<String> = decoders
+        .iter()
+        .filter(|(_, formats)| {
+            formats
+                .iter()
+                .all(|format| match gst::Caps::from_string(format) {
+                    Some(caps) => !gst::ElementFactory::list_filter(
+                        &factories,
+                        &caps,
+                        gst::PadDirection::Sink,
+                        false,
+                    )
+                    .is_empty(),
+                    None => false,
+                })
+        })
+        .map(|(code, _)| (*code).to_owned())
+        .collect();
+
+    if caps.is_empty() {
+        warn!("No decoders detected");
+    }
+    caps
+}
+
+// A pipeline description such as "audioconvert ! pipewiresink", whose first free pad takes
+// the audio
+fn parse_sink(description: &str) -> Result<gst::Element, String> {
+    let bin = gst::parse_bin_from_description(description, true)
+        .map_err(|e| format!("{}: {}", description, e))?;
+
+    if bin.get_static_pad("sink").is_none() {
+        return Err(format!("{}: has nowhere to send the audio", description));
+    }
+    if bin.get_static_pad("src").is_some() {
+        return Err(format!("{}: does not end in a sink", description));
+    }
+
+    bin.set_name("sink")
+        .map_err(|_| format!("{}: unable to name the pipeline", description))?;
+    Ok(bin.upcast())
+}
+
+// The encoder for a file, going by its extension, or None for raw PCM
+fn file_encoder(path: &str) -> Option<&'static str> {
+    let extension = Path::new(path)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    match extension.as_ref().map(|extension| extension.as_str()) {
+        Some("wav") => Some("wavenc"),
+        Some("flac") => Some("flacenc"),
+        _ => None,
+    }
+}
+
+fn make_file_sink(path: &str, raw_format: &RawFormat) -> Option<gst::Element> {
+    let sink = gst::ElementFactory::make("filesink", None)?;
+    sink.set_property("location", &path).ok()?;
+
+    match file_encoder(path) {
+        // The file is started again each time the server stops playing
+        Some(encoder) => {
+            let converter = gst::ElementFactory::make("audioconvert", None)?;
+            let encoder = gst::ElementFactory::make(encoder, None)?;
+            make_sink_bin(&[&converter, &encoder, &sink])
+        }
+        // Raw PCM may be going to a FIFO, which must not be cut off
+        None => {
+            sink.set_property("append", &true).ok()?;
+            make_raw_sink(sink, raw_format)
+        }
+    }
+}
+
+fn make_raw_sink(sink: gst::Element, raw_format: &RawFormat) -> Option<gst::Element> {
+    let converter = gst::ElementFactory::make("audioconvert", None)?;
+    let filter = gst::ElementFactory::make("capsfilter", None)?;
+    filter.set_property("caps", &raw_format.caps()?).ok()?;
+    make_sink_bin(&[&converter, &filter, &sink])
+}
+
+// Wrap up elements ending in a sink so they look like any other audio sink. Files and pipes
+// have no clock of their own, so they are written at the pace of the pipeline's
+fn make_sink_bin(elements: &[&gst::Element]) -> Option<gst::Element> {
+    let bin = gst::Bin::new(Some("sink"));
+    bin.add_many(elements).ok()?;
+    gst::Element::link_many(elements).ok()?;
+
+    let last = elements.last()?;
+    last.set_property("sync", &true).ok()?;
+
+    let pad = elements.first()?.get_static_pad("sink")?;
+    let ghost = gst::GhostPad::new(Some("sink"), &pad)?;
+    bin.add_pad(&ghost).ok()?;
+    Some(bin.upcast())
+}
+
+pub fn max_sample_rate(output_device: &AudioDevice) -> Option<u32> {
+    // Anything above this is unlikely to be real and would just make the server do more work
+    const RATE_LIMIT: i32 = 384_000;
+
+    let rate = query_caps(output_device).and_then(|caps| {
+        caps.iter().fold(None, |max: Option<i32>, structure| {
+            let rate = if let Some(rate) = structure.get::<i32>("rate") {
+                Some(rate)
+            } else if let Some(range) = structure.get::<gst::IntRange<i32>>("rate") {
+                Some(range.max())
+            } else if let Some(list) = structure.get::<gst::List>("rate") {
+                list.as_slice().iter().filter_map(|v| v.get::<i32>()).max()
+            } else {
+                None
+            };
+            match (max, rate) {
+                (Some(max), Some(rate)) if rate > max => Some(rate),
+                (None, rate) => rate,
+                (max, _) => max,
+            }
+        })
+    });
+
+    rate.map(|rate| rate.min(RATE_LIMIT) as u32)
+}
+
+// Open the device just long enough to ask it what it can do. Files and pipes are left alone,
+// as opening them starts them afresh, and raw ones are converted to the rate they were given
+fn query_caps(output_device: &AudioDevice) -> Option<gst::Caps> {
+    if gst::init().is_err() {
+        return None;
+    }
+    match output_device.service {
+        AudioService::File | AudioService::Stdout if !output_device.is_encoded() => {
+            return output_device.raw_format.caps();
+        }
+        AudioService::File | AudioService::Stdout | AudioService::Gst => return None,
+        _ => (),
+    }
+
+    let sink = make_sink(output_device)?;
+    if sink.set_state(gst::State::Ready).is_err() {
+        let _ = sink.set_state(gst::State::Null);
+        return None;
+    }
+
+    let caps = sink.get_static_pad("sink").map(|pad| pad.query_caps(None));
+    let _ = sink.set_state(gst::State::Null);
+    caps
+}
+
+/// An output device found on the system.
+pub struct OutputInfo {
+    /// As it would be given to --output.
+    pub device: String,
+    pub description: String,
+    pub formats: Vec<String>,
+    /// Single rates or ranges, such as "44100" or "8000-192000".
+    pub rates: Vec<String>,
+}
+
+impl OutputInfo {
+    fn new(device: String, description: String, caps: Option<gst::Caps>) -> Self {
+        let mut output = OutputInfo {
+            device: device,
+            description: description,
+            formats: Vec::new(),
+            rates: Vec::new(),
+        };
+
+        for structure in caps.iter().flat_map(|caps| caps.iter()) {
+            if structure.get_name() != "audio/x-raw" {
+                continue;
+            }
+
+            let formats = if let Some(format) = structure.get::<String>("format") {
+                vec![format]
+            } else if let Some(list) = structure.get::<gst::List>("format") {
+                list.as_slice()
+                    .iter()
+                    .filter_map(|v| v.get::<String>())
+                    .collect()
+            } else {
+                Vec::new()
+            };
+
+            let rates = if let Some(rate) = structure.get::<i32>("rate") {
+                vec![rate.to_string()]
+            } else if let Some(range) = structure.get::<gst::IntRange<i32>>("rate") {
+                vec![format!("{}-{}", range.min(), range.max())]
+            } else if let Some(list) = structure.get::<gst::List>("rate") {
+                list.as_slice()
+                    .iter()
+                    .filter_map(|v| v.get::<i32>())
+                    .map(|rate| rate.to_string())
+                    .collect()
+            } else {
+                Vec::new()
+            };
+
+            for format in formats {
+                if !output.formats.contains(&format) {
+                    output.formats.push(format);
+                }
+            }
+            for rate in rates {
+                if !output.rates.contains(&rate) {
+                    output.rates.push(rate);
+                }
+            }
+        }
+
+        output
+    }
+}
+
+/// Every output device that can be found, from GStreamer's device monitor and ALSA's list of
+/// cards.
+pub fn list_outputs() -> Vec<OutputInfo> {
+    if let Err(e) = gst::init() {
+        error!("Unable to initialise GStreamer: {}", e);
+        return Vec::new();
+    }
+
+    let mut outputs = Vec::new();
+    let cards = fs::read_to_string("/proc/asound/cards")
+        .map(|cards| parse_cards(&cards))
+        .unwrap_or_default();
+
+    let monitor = gst::DeviceMonitor::new();
+    monitor.add_filter(Some("Audio/Sink"), None);
+    if monitor.start().is_ok() {
+        for device in monitor.get_devices() {
+            let element = match device.create_element(None) {
+                Some(element) => element,
+                None => continue,
+            };
+            let factory = match element.get_factory() {
+                Some(factory) => factory.get_name(),
+                None => continue,
+            };
+            let name = element
+                .get_property("device")
+                .ok()
+                .and_then(|value| value.get::<String>());
+
+            let device_name = match (factory.as_str(), name) {
+                ("alsasink", Some(name)) => format!("alsa#{}", alsa_device_name(&name, &cards)),
+                ("pulsesink", Some(name)) => format!("pulse#{}", name),
+                (factory, Some(name)) => format!("gst#{} device=\"{}\"", factory, name),
+                (factory, None) => format!("gst#{}", factory),
+            };
+            outputs.push(OutputInfo::new(
+                device_name,
+                device.get_display_name().to_string(),
+                device.get_caps(),
+            ));
+        }
+        monitor.stop();
+    } else {
+        warn!("Unable to start the GStreamer device monitor");
+    }
+
+    for output in alsa_outputs(&cards) {
+        if !outputs.iter().any(|o| o.device == output.device) {
+            outputs.push(output);
         }
+    }
+
+    outputs
+}
+
+// The sound cards and PCMs the kernel knows of, then those ALSA's configuration adds, such as
+// dmix, softvol and anything in .asoundrc
+fn alsa_outputs(cards: &[(u32, String)]) -> Vec<OutputInfo> {
+    let pcms = fs::read_to_string("/proc/asound/pcm")
+        .map(|pcms| parse_pcms(&pcms))
+        .unwrap_or_default();
+
+    let mut outputs = Vec::new();
+    for pcm in pcms {
+        // Names stay the same when cards are plugged in a different order, numbers do not
+        let card = card_id(pcm.card, cards);
+
+        // Only the hardware device has formats and rates of its own, plughw converts to them
+        let hw = AudioDevice {
+            service: AudioService::Alsa,
+            device: Some(format!("hw:CARD={},DEV={}", card, pcm.device)),
+            ..AudioDevice::default()
+        };
+        outputs.push(OutputInfo::new(
+            format!("alsa#hw:CARD={},DEV={}", card, pcm.device),
+            format!("{}, {}", card, pcm.name),
+            query_caps(&hw),
+        ));
+        outputs.push(OutputInfo::new(
+            format!("alsa#plughw:CARD={},DEV={}", card, pcm.device),
+            format!("{}, {}, with conversion", card, pcm.name),
+            None,
+        ));
+    }
+
+    for (name, description) in alsa_hints() {
+        let device = format!("alsa#{}", alsa_device_name(&name, cards));
+        if name != "null" && !outputs.iter().any(|output| output.device == device) {
+            outputs.push(OutputInfo::new(device, description, None));
+        }
+    }
+    outputs
+}
+
+// The PCMs ALSA's configuration has hints for that can play
+fn alsa_hints() -> Vec<(String, String)> {
+    let hints = match alsa::device_name::HintIter::new(None, &CString::new("pcm").unwrap()) {
+        Ok(hints) => hints,
+        Err(e) => {
+            warn!("Unable to list ALSA devices: {}", e);
+            return Vec::new();
+        }
+    };
+
+    hints
+        .filter(|hint| match hint.direction {
+            Some(alsa::Direction::Capture) => false,
+            _ => true,
+        })
+        .filter_map(|hint| {
+            let name = hint.name?;
+            // Descriptions are a line for the card and one for the device
+            let description = hint
+                .desc
+                .map(|desc| desc.replace('\n', ", "))
+                .unwrap_or_else(|| name.clone());
+            Some((name, description))
+        })
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+struct AlsaPcm {
+    card: u32,
+    device: u32,
+    name: String,
+}
+
+// The kernel's list of PCMs, with lines like:
+// 00-03: HDMI 0 : HDMI 0 : playback 1
+fn parse_pcms(pcms: &str) -> Vec<AlsaPcm> {
+    let mut found = Vec::new();
+    for line in pcms.lines() {
+        let fields: Vec<&str> = line.split(':').map(|field| field.trim()).collect();
+        if fields.len() < 3 || !fields.iter().any(|field| field.starts_with("playback")) {
+            continue;
+        }
+
+        let mut numbers = fields[0].split('-').map(|n| n.parse::<u32>());
+        if let (Some(Ok(card)), Some(Ok(device))) = (numbers.next(), numbers.next()) {
+            found.push(AlsaPcm {
+                card: card,
+                device: device,
+                name: fields[1].to_owned(),
+            });
+        }
+    }
+    found
+}
+
+// The kernel's list of cards, with the number and id of each starting a line like:
+//  0 [Headphones     ]: bcm2835_headpho - bcm2835 Headphones
+fn parse_cards(cards: &str) -> Vec<(u32, String)> {
+    cards
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.trim_start().splitn(2, '[');
+            let number = fields.next()?.trim().parse::<u32>().ok()?;
+            let id = fields.next()?.split(']').next()?.trim();
+            Some((number, id.to_owned()))
+        })
+        .collect()
+}
+
+fn card_id(card: u32, cards: &[(u32, String)]) -> String {
+    cards
+        .iter()
+        .find(|&&(number, _)| number == card)
+        .map(|&(_, ref id)| id.clone())
+        .unwrap_or_else(|| card.to_string())
+}
+
+// Hardware devices can be named by the card's number or its id and with or without the
+// device, e.g. hw:0 and hw:CARD=Headphones,DEV=0, so they are all put in the second form
+fn alsa_device_name(name: &str, cards: &[(u32, String)]) -> String {
+    let mut parts = name.splitn(2, ':');
+    let (kind, args) = match (parts.next(), parts.next()) {
+        (Some(kind), Some(args)) if kind == "hw" || kind == "plughw" => (kind, args),
+        _ => return name.to_owned(),
+    };
+
+    let (mut card, mut device) = (None, "0");
+    for (n, arg) in args.split(',').enumerate() {
+        let mut pair = arg.splitn(2, '=');
+        match (pair.next(), pair.next(), n) {
+            (Some("CARD"), Some(value), _) | (Some(value), None, 0) => card = Some(value),
+            (Some("DEV"), Some(value), _) | (Some(value), None, 1) => device = value,
+            _ => return name.to_owned(),
+        }
+    }
+
+    let card = match card {
+        Some(card) => match card.parse::<u32>() {
+            Ok(number) => card_id(number, cards),
+            Err(_) => card.to_owned(),
+        },
+        None => return name.to_owned(),
+    };
+    format!("{}:CARD={},DEV={}", kind, card, device)
+}
+
+struct HttpRequest {
+    path: String,
+    host: Option<String>,
+    user_agent: Option<String>,
+    headers: Vec<(String, String)>,
+}
+
+impl<'a> From<&'a str> for HttpRequest {
+    fn from(http_headers: &str) -> Self {
+        let mut lines = http_headers.lines();
+
+        let path = match lines.next() {
+            Some(line) => line.split_whitespace().nth(1).unwrap_or("").to_owned(),
+            None => String::new(),
+        };
+
+        let mut request = HttpRequest {
+            path: path,
+            host: None,
+            user_agent: None,
+            headers: Vec::new(),
+        };
+
+        for line in lines {
+            let mut header = line.splitn(2, ':');
+            let (name, value) = match (header.next(), header.next()) {
+                (Some(name), Some(value)) => (name.trim(), value.trim()),
+                _ => continue,
+            };
+
+            // souphttpsrc sets these from its location and user-agent properties
+            match name.to_lowercase().as_str() {
+                "host" => request.host = Some(value.to_owned()),
+                "user-agent" => request.user_agent = Some(value.to_owned()),
+                _ => request.headers.push((name.to_owned(), value.to_owned())),
+            }
+        }
+
+        request
+    }
+}
+
+impl HttpRequest {
+    // The Host header names the server when there is one, but only has a port in it when that
+    // is not the usual one for the scheme
+    fn location(&self, use_ssl: bool, server_ip: Ipv4Addr, server_port: u16) -> String {
+        let (scheme, default_port) = if use_ssl || server_port == 443 {
+            ("https", 443)
+        } else {
+            ("http", 80)
+        };
+        let host = match self.host {
+            Some(ref host) if has_port(host) || server_port == default_port => host.clone(),
+            Some(ref host) => format!("{}:{}", host, server_port),
+            None => format!("{}:{}", server_ip, server_port),
+        };
+        format!("{}://{}{}", scheme, host, self.path)
+    }
+
+    // Headers given more than once, such as Cookie, are all sent
+    fn extra_headers(&self) -> Vec<(String, Vec<String>)> {
+        let mut headers: Vec<(String, Vec<String>)> = Vec::new();
+        for (name, value) in self.headers.iter() {
+            match headers.iter_mut().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+                Some((_, values)) => values.push(value.clone()),
+                None => headers.push((name.clone(), vec![value.clone()])),
+            }
+        }
+        headers
+    }
+}
+
+// A port follows the last colon, unless that colon is inside an IPv6 address
+fn has_port(host: &str) -> bool {
+    match host.rfind(':') {
+        Some(colon) => {
+            let port = &host[colon + 1..];
+            !port.is_empty() && port.bytes().all(|c| c.is_ascii_digit())
+        }
+        None => false,
+    }
+}
+
+// Milliseconds of the current track that have been heard
+fn track_position(pipeline: &gst::Pipeline, tracks: &Mutex<VecDeque<u64>>) -> u64 {
+    let running = match query_running_time(pipeline) {
+        Some(running) => running,
+        None => return 0,
+    };
+
+    let mut tracks = tracks.lock().unwrap();
+    while tracks.len() > 1 && tracks[1] <= running {
+        tracks.pop_front();
+    }
+
+    match tracks.front() {
+        Some(start) if *start <= running => (running - start) / 1_000_000,
+        _ => 0,
+    }
+}
+
+// How much earlier a segment has been moved, by comparing the running times each gives the
+// start of the later one
+fn skipped_time(
+    before: &gst::FormattedSegment<gst::ClockTime>,
+    after: &gst::FormattedSegment<gst::ClockTime>,
+) -> u64 {
+    let start = after.get_start();
+    match (
+        before.to_running_time(start).nseconds(),
+        after.to_running_time(start).nseconds(),
+    ) {
+        (Some(before), Some(after)) if after < before => before - after,
+        _ => 0,
+    }
+}
+
+// The running time of the audio currently coming out of the sink
+fn query_running_time(pipeline: &gst::Pipeline) -> Option<u64> {
+    let (_, state, _) = pipeline.get_state(gst::ClockTime::from_mseconds(0));
+    let running = match state {
+        gst::State::Playing => clock()
+            .get_time()
+            .nseconds()?
+            .saturating_sub(pipeline.get_base_time().nseconds()?),
+        gst::State::Paused => pipeline.get_start_time().nseconds()?,
+        _ => return None,
+    };
+    Some(running.saturating_sub(query_latency(pipeline)))
+}
+
+fn query_latency(pipeline: &gst::Pipeline) -> u64 {
+    let mut q = gst::Query::new_latency();
+    if pipeline.query(&mut q) {
+        let (_, min, _) = q.get_result();
+        min.nseconds().unwrap_or(0)
     } else {
         0
     }
@@ -663,3 +2084,292 @@ fn block(bin: gst::Bin) {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+    use testing;
+    use std::env;
+    use std::io::{Read, Write};
+    use std::net;
+    use std::process;
+    use std::sync;
+
+    const SERVER: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 10);
+
+    #[test]
+    fn request_is_parsed() {
+        let request = HttpRequest::from(
+            "GET /stream.mp3?player=00:11 HTTP/1.0\r\nHost: radio.example.com\r\n\
+             User-Agent: iTunes/4.7.1\r\nIcy-MetaData: 1\r\n\r\n",
+        );
+        assert_eq!(request.path, "/stream.mp3?player=00:11");
+        assert_eq!(request.host, Some("radio.example.com".to_owned()));
+        assert_eq!(request.user_agent, Some("iTunes/4.7.1".to_owned()));
+        assert_eq!(
+            request.headers,
+            vec![("Icy-MetaData".to_owned(), "1".to_owned())]
+        );
+    }
+
+    #[test]
+    fn server_is_used_without_host() {
+        let request = HttpRequest::from("GET /stream.mp3 HTTP/1.0\r\n\r\n");
+        assert_eq!(
+            request.location(false, SERVER, 9000),
+            "http://192.168.1.10:9000/stream.mp3"
+        );
+    }
+
+    #[test]
+    fn host_keeps_server_port() {
+        let request = HttpRequest::from("GET /live HTTP/1.0\r\nHost: radio.example.com\r\n");
+        assert_eq!(
+            request.location(false, SERVER, 8000),
+            "http://radio.example.com:8000/live"
+        );
+        assert_eq!(
+            request.location(false, SERVER, 80),
+            "http://radio.example.com/live"
+        );
+    }
+
+    #[test]
+    fn host_port_is_kept() {
+        let request =
+            HttpRequest::from("GET /live HTTP/1.0\r\nHost: radio.example.com:8443\r\n");
+        assert_eq!(
+            request.location(true, SERVER, 8443),
+            "https://radio.example.com:8443/live"
+        );
+
+        let request = HttpRequest::from("GET /live HTTP/1.0\r\nHost: [2001:db8::1]\r\n");
+        assert_eq!(
+            request.location(false, SERVER, 8000),
+            "http://[2001:db8::1]:8000/live"
+        );
+    }
+
+    #[test]
+    fn https_from_flag_or_port() {
+        let request = HttpRequest::from("GET /live HTTP/1.0\r\nHost: radio.example.com\r\n");
+        assert_eq!(
+            request.location(true, SERVER, 443),
+            "https://radio.example.com/live"
+        );
+        assert_eq!(
+            request.location(true, SERVER, 8443),
+            "https://radio.example.com:8443/live"
+        );
+        assert_eq!(
+            request.location(false, SERVER, 443),
+            "https://radio.example.com/live"
+        );
+    }
+
+    #[test]
+    fn skips_are_found_from_segments() {
+        gst::init().unwrap();
+        let mut before = gst::FormattedSegment::<gst::ClockTime>::new();
+        before.set_base(gst::ClockTime::from_seconds(10));
+
+        let mut after = before.clone();
+        after.offset_running_time(-3_000_000_000).unwrap();
+        assert_eq!(skipped_time(&before, &after), 3_000_000_000);
+
+        // Once the base can go no lower the start of the segment is moved on instead
+        let mut after = before.clone();
+        after.offset_running_time(-12_000_000_000).unwrap();
+        assert_eq!(skipped_time(&before, &after), 12_000_000_000);
+
+        // A new track starts later
+        let mut after = before.clone();
+        after.set_base(gst::ClockTime::from_seconds(60));
+        assert_eq!(skipped_time(&before, &after), 0);
+    }
+
+    // Passes on what a player reports
+    struct Reports(sync::mpsc::Sender<PlayerMessages>);
+
+    impl actix::Actor for Reports {
+        type Context = actix::Context<Self>;
+    }
+
+    impl actix::Handler<PlayerMessages> for Reports {
+        type Result = ();
+
+        fn handle(&mut self, msg: PlayerMessages, _ctx: &mut actix::Context<Self>) {
+            let _ = self.0.send(msg);
+        }
+    }
+
+    // A wav file of silence, 16 bit stereo at 44.1kHz
+    fn wav(seconds: u32) -> Vec<u8> {
+        let len = seconds * 44100 * 4;
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&u32_le(36 + len));
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&[16, 0, 0, 0, 1, 0, 2, 0]);
+        wav.extend_from_slice(&u32_le(44100));
+        wav.extend_from_slice(&u32_le(44100 * 4));
+        wav.extend_from_slice(&[4, 0, 16, 0]);
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&u32_le(len));
+        wav.resize((44 + len) as usize, 0);
+        wav
+    }
+
+    fn u32_le(n: u32) -> [u8; 4] {
+        [n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]
+    }
+
+    // Skipping over any other reports on the way
+    fn wait_for_report<F: Fn(&PlayerMessages) -> bool>(
+        reports: &sync::mpsc::Receiver<PlayerMessages>,
+        wanted: F,
+    ) {
+        testing::wait_for(|| reports.try_recv().map_or(false, |msg| wanted(&msg)));
+    }
+
+    #[test]
+    fn file_is_finished_after_pause_and_stop() {
+        init().unwrap();
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request);
+            let body = wav(5);
+            let _ = write!(
+                stream,
+                "HTTP/1.0 200 OK\r\nContent-Type: audio/x-wav\r\nContent-Length: {}\r\n\r\n",
+                body.len()
+            );
+            let _ = stream.write_all(&body);
+        });
+
+        let path = env::temp_dir().join(format!("storm-test-{}.wav", process::id()));
+        let output = AudioDevice::from(vec!["file", path.to_str().unwrap()]);
+
+        let sys = actix::System::new("test");
+        let (sender, reports) = sync::mpsc::channel();
+        let proto = actix::Actor::start(Reports(sender)).recipient();
+        let player = actix::Actor::start(Player::new(proto, output, DeviceRelease::default()));
+        testing::run(sys, move || {
+            player.do_send(PlayerControl::Enable(true));
+            player.do_send(PlayerControl::Stream {
+                autostart: true,
+                threshold: 64 * 1024,
+                output_threshold: 0,
+                replay_gain: 0.0,
+                server_port: port,
+                server_ip: Ipv4Addr::LOCALHOST,
+                control_ip: Ipv4Addr::LOCALHOST,
+                use_ssl: false,
+                http_headers: "GET /silence.wav HTTP/1.0\r\n\r\n".to_owned(),
+            });
+            wait_for_report(&reports, |msg| match msg {
+                PlayerMessages::Start => true,
+                _ => false,
+            });
+            thread::sleep(time::Duration::from_millis(500));
+
+            // The server pauses before it stops
+            player.do_send(PlayerControl::Pause(false));
+            wait_for_report(&reports, |msg| match msg {
+                PlayerMessages::Paused => true,
+                _ => false,
+            });
+            player.send(PlayerControl::Stop).wait().unwrap();
+        });
+        server.join().unwrap();
+
+        let written = fs::read(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        let field = |at: usize| {
+            written[at..at + 4]
+                .iter()
+                .rev()
+                .fold(0, |n, &b| n << 8 | b as usize)
+        };
+        let data = written
+            .windows(4)
+            .position(|chunk| chunk == b"data")
+            .expect("No data in the file");
+        assert!(written.len() > data + 8);
+        assert_eq!(&written[..4], b"RIFF");
+        assert_eq!(field(4), written.len() - 8);
+        assert_eq!(field(data + 4), written.len() - data - 8);
+    }
+
+    #[test]
+    fn repeated_headers_are_all_sent() {
+        let request = HttpRequest::from(
+            "GET /live HTTP/1.0\r\nCookie: a=1\r\nAccept: */*\r\ncookie: b=2\r\n",
+        );
+        assert_eq!(
+            request.extra_headers(),
+            vec![
+                (
+                    "Cookie".to_owned(),
+                    vec!["a=1".to_owned(), "b=2".to_owned()]
+                ),
+                ("Accept".to_owned(), vec!["*/*".to_owned()]),
+            ]
+        );
+    }
+
+    fn cards() -> Vec<(u32, String)> {
+        vec![(0, "PCH".to_owned()), (1, "Headphones".to_owned())]
+    }
+
+    #[test]
+    fn playback_pcms_are_parsed() {
+        let pcms = "00-00: ALC892 Analog : ALC892 Analog : playback 1 : capture 1\n\
+                    00-02: ALC892 Alt Analog : ALC892 Alt Analog : capture 1\n\
+                    01-03: HDMI 0 : HDMI 0 : playback 1\n\
+                    garbage\n";
+        assert_eq!(
+            parse_pcms(pcms),
+            vec![
+                AlsaPcm {
+                    card: 0,
+                    device: 0,
+                    name: "ALC892 Analog".to_owned(),
+                },
+                AlsaPcm {
+                    card: 1,
+                    device: 3,
+                    name: "HDMI 0".to_owned(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn cards_are_parsed() {
+        let cards = " 0 [PCH            ]: HDA-Intel - HDA Intel PCH\n\
+                     \x20                     HDA Intel PCH at 0xf7f10000 irq 32\n\
+                     \x201 [Headphones     ]: bcm2835_headpho - bcm2835 Headphones\n\
+                     \x20                     bcm2835 Headphones\n";
+        assert_eq!(parse_cards(cards), cards());
+    }
+
+    #[test]
+    fn hardware_names_are_made_the_same() {
+        let cards = cards();
+        for name in &["hw:1", "hw:1,0", "hw:Headphones", "hw:CARD=Headphones,DEV=0"] {
+            assert_eq!(alsa_device_name(name, &cards), "hw:CARD=Headphones,DEV=0");
+        }
+        assert_eq!(alsa_device_name("plughw:0,3", &cards), "plughw:CARD=PCH,DEV=3");
+        assert_eq!(alsa_device_name("hw:CARD=PCH", &cards), "hw:CARD=PCH,DEV=0");
+        // Cards that have gone are left as numbers
+        assert_eq!(alsa_device_name("hw:5", &cards), "hw:CARD=5,DEV=0");
+        for name in &["dmix:CARD=PCH,DEV=0", "default", "softvol", "hw:CARD=PCH,SUBDEV=1"] {
+            assert_eq!(alsa_device_name(name, &cards), *name);
+        }
+    }
+}
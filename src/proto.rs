@@ -1,48 +1,243 @@
 use actix;
-use actix::{Actor, ActorContext, Arbiter, AsyncContext, Context, System};
+use actix::{Actor, ActorContext, Arbiter, AsyncContext, Context, System, SystemService};
+use futures::sync::mpsc;
 use futures::{future, Future, Sink, Stream};
+use get_if_addrs;
+use libc;
 use mac_address;
-use regex::RegexSetBuilder;
 use tokio_codec::FramedRead;
 use tokio_core;
 use tokio_io::io::WriteHalf;
 use tokio_io::AsyncRead;
-use tokio_signal::unix::{Signal, SIGTERM};
+use tokio_signal::unix::{Signal, SIGHUP, SIGTERM};
 use tokio_tcp::TcpStream;
 use tokio_timer;
 
+use api;
+use attach;
+use backend;
 use codec;
+use display;
+use hooks;
+use input;
+#[cfg(feature = "mpris")]
+use mpris;
+use mqtt;
 use player;
 
+use std::fs;
 use std::io;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::process::Command;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+// Where servers listen for players
+pub const SLIMPROTO_PORT: u16 = 3483;
+const WIRELESS_PATH: &str = "/proc/net/wireless";
+// What a wired connection reports in place of a signal strength
+const WIRED_SIGNAL_STRENGTH: u16 = 0xffff;
+// The server's usual command line interface port, used for things only the server can do
+const CLI_PORT: u16 = 9090;
+// Buffer levels change all the time whilst playing, so they are only published this often, in ms
+const MQTT_BUFFER_INTERVAL: u64 = 1000;
+
+pub struct Profile {
+    pub name: &'static str,
+    device_id: u8,
+    revision: u8,
+    model: &'static str,
+    model_name: Option<&'static str>,
+    pub display: Option<display::Kind>,
+}
+
+// Players the server knows about that Storm can pretend to be. Real hardware reports the
+// revision of the last firmware released for it, as with anything older the server offers
+// to upgrade the firmware
+pub const PROFILES: &[Profile] = &[
+    Profile {
+        name: "storm",
+        device_id: 12,
+        revision: 0,
+        model: "Storm",
+        model_name: None,
+        display: None,
+    },
+    Profile {
+        name: "squeezelite",
+        device_id: 12,
+        revision: 0,
+        model: "squeezelite",
+        model_name: Some("SqueezeLite"),
+        display: None,
+    },
+    Profile {
+        name: "squeezeplay",
+        device_id: 12,
+        revision: 0,
+        model: "squeezeplay",
+        model_name: Some("SqueezePlay"),
+        display: None,
+    },
+    Profile {
+        name: "receiver",
+        device_id: 7,
+        revision: 77,
+        model: "receiver",
+        model_name: Some("Squeezebox Receiver"),
+        display: None,
+    },
+    Profile {
+        name: "squeezebox",
+        device_id: 2,
+        revision: 40,
+        model: "squeezebox",
+        model_name: Some("Squeezebox"),
+        display: Some(display::Kind::Text),
+    },
+    Profile {
+        name: "squeezebox2",
+        device_id: 4,
+        revision: 137,
+        model: "squeezebox2",
+        model_name: Some("Squeezebox2"),
+        display: Some(display::Kind::Graphic {
+            width: 320,
+            height: 32,
+        }),
+    },
+    Profile {
+        name: "boom",
+        device_id: 10,
+        revision: 57,
+        model: "boom",
+        model_name: Some("Squeezebox Boom"),
+        display: Some(display::Kind::Graphic {
+            width: 160,
+            height: 32,
+        }),
+    },
+];
+
+pub fn profile(name: &str) -> Option<&'static Profile> {
+    PROFILES.iter().find(|p| p.name == name.to_lowercase())
+}
+
+// Settings given at startup that stay the same for every connection to a server
+#[derive(Clone)]
+pub struct Options {
+    pub bufsize: Option<u32>,
+    pub output_device: player::AudioDevice,
+    pub codecs: Option<Vec<String>>,
+    pub extra_caps: Vec<String>,
+    pub profile: &'static Profile,
+    pub device_release: player::DeviceRelease,
+    pub event_hook: Option<String>,
+    pub api_port: Option<u16>,
+    pub cli_port: u16,
+    pub mpris: bool,
+    pub mqtt_broker: Option<String>,
+    pub input: Option<input::Device>,
+    pub keymap: input::Keymap,
+    pub display: Option<display::Output>,
+    pub events: Option<mpsc::UnboundedSender<hooks::Event>>,
+    pub backend: Arc<dyn backend::Backend>,
+    pub output_config: Option<PathBuf>,
+    // Worked out when starting, rather than on every connection
+    pub max_sample_rate: Option<u32>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            bufsize: None,
+            output_device: player::AudioDevice::default(),
+            codecs: None,
+            extra_caps: Vec::new(),
+            profile: &PROFILES[0],
+            device_release: player::DeviceRelease::default(),
+            event_hook: None,
+            api_port: None,
+            cli_port: CLI_PORT,
+            mpris: false,
+            mqtt_broker: None,
+            input: None,
+            keymap: input::Keymap::default(),
+            display: None,
+            events: None,
+            backend: Arc::new(backend::Gstreamer),
+            output_config: None,
+            max_sample_rate: None,
+        }
+    }
+}
+
 pub struct Proto {
     sync_group_id: Option<String>,
-    creation_time: Instant,
+    position: u64,
+    position_time: u64,
+    playing: bool,
+    started: bool,
+    streaming: bool,
+    output_underrun: bool,
+    output_underruns: u32,
+    input_underruns: u32,
     stat_data: codec::StatData,
     server_ip: Ipv4Addr,
+    server_port: u16,
+    interface: Option<String>,
     name: String,
-    output_device: player::AudioDevice,
+    options: Options,
+    // The sample rate of a device being switched to, kept until the player is using it
+    new_sample_rate: Option<u32>,
+    track: player::TrackInfo,
+    power: bool,
+    volume: f64,
     autostart: bool,
-    player: actix::Addr<player::Player>,
+    display: Option<display::Display>,
+    scroll: Option<actix::SpawnHandle>,
+    visualiser: Option<display::Visualiser>,
+    visu_data: display::VisuData,
+    // When buffer levels were last published over MQTT
+    buffers_published: Instant,
+    player: actix::Recipient<player::PlayerControl>,
     framed: actix::io::FramedWrite<WriteHalf<TcpStream>, codec::SlimCodec>,
 }
 
 impl Actor for Proto {
     type Context = Context<Self>;
 
-    fn started(&mut self, _ctx: &mut Context<Self>) {
-        let name = format!("ModelName={}", self.name);
-        let caps = get_decode_caps();
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        match self.interface {
+            Some(ref interface) => info!("Connected to server through: {}", interface),
+            None => warn!("Unable to find the network interface connected to the server"),
+        }
+        attach::attach(&ctx.address(), &self.options);
+
+        self.update_signal_strength();
+        ctx.run_interval(Duration::from_secs(1), |proto, _| {
+            proto.update_signal_strength()
+        });
+
+        let model = format!("Model={}", self.options.profile.model);
+        let name = match self.options.profile.model_name {
+            Some(model_name) => format!("ModelName={}", model_name),
+            None => format!("ModelName={}", self.name),
+        };
+        let firmware = format!("Firmware={}", env!("CARGO_PKG_VERSION"));
+        let caps = match self.options.codecs {
+            Some(ref codecs) => codecs.clone(),
+            None => self.options.backend.decode_caps(),
+        };
         let player_caps: &[&str] = &[
-            "Model=Storm",
+            model.as_str(),
             name.as_str(),
+            firmware.as_str(),
             "AccuratePlayPoints=1",
             "HasDigitalOut=1",
             "HasPolarityInversion=1",
+            "Balance=1",
+            "CanHTTPS=1",
         ];
         info!("Available docoders: {}", caps.join(","));
 
@@ -57,15 +252,33 @@ impl Actor for Proto {
             caps.push(format!("SyncgroupID={}", sync_group));
         }
 
+        if let Some(rate) = self.options.max_sample_rate {
+            caps.push(format!("MaxSampleRate={}", rate));
+        }
+
+        // Capabilities given by the user override any we have worked out ourselves
+        caps.retain(|cap| {
+            !self
+                .options
+                .extra_caps
+                .iter()
+                .any(|extra| extra.split('=').nth(0) == cap.split('=').nth(0))
+        });
+        caps.extend(self.options.extra_caps.iter().cloned());
+
         let mac = get_mac();
         info!("Using MAC address: {}", mac);
 
+        info!(
+            "Using player profile: {}, device id: {}",
+            self.options.profile.name, self.options.profile.device_id
+        );
         let helo = codec::ClientMessage::Helo {
-            device_id: 12,
-            revision: 0,
+            device_id: self.options.profile.device_id,
+            revision: self.options.profile.revision,
             mac: mac,
             uuid: [0; 16],
-            wlan_channel_list: 0,
+            wlan_channel_list: self.interface.as_ref().map_or(0, |i| wlan_channel_list(i)),
             bytes_received: 0,
             capabilities: caps.join(","),
         };
@@ -93,10 +306,13 @@ impl actix::StreamHandler<codec::ServerMessage, io::Error> for Proto {
                 info!("Got serv message");
                 spawn_proto(
                     ip_address,
+                    SLIMPROTO_PORT,
                     sync_group_id,
                     self.name.as_str(),
-                    Some(self.stat_data.buffer_size),
-                    self.output_device.clone(),
+                    Options {
+                        bufsize: Some(self.stat_data.buffer_size),
+                        ..self.options.clone()
+                    },
                 );
                 ctx.stop();
             }
@@ -104,7 +320,7 @@ impl actix::StreamHandler<codec::ServerMessage, io::Error> for Proto {
             codec::ServerMessage::Status(timestamp) => {
                 info!("Got status request");
                 self.stat_data.timestamp = timestamp;
-                self.stat_data.jiffies = self.jiffies();
+                self.update_play_point();
                 self.framed.write(self.stat_data.make_stat_message("STMt"));
             }
 
@@ -115,6 +331,7 @@ impl actix::StreamHandler<codec::ServerMessage, io::Error> for Proto {
                 replay_gain,
                 server_port,
                 server_ip,
+                use_ssl,
                 http_headers,
             } => {
                 info!("Got stream start");
@@ -129,9 +346,11 @@ impl actix::StreamHandler<codec::ServerMessage, io::Error> for Proto {
                 self.stat_data.fullness = 0;
                 self.stat_data.output_buffer_fullness = 0;
                 self.stat_data.crlf = 0;
+                self.stat_data.error_code = 0;
                 self.autostart = autostart;
+                self.streaming = true;
                 self.framed.write(self.stat_data.make_stat_message("STMc"));
-                self.player.do_send(player::PlayerControl::Stream {
+                let _ = self.player.do_send(player::PlayerControl::Stream {
                     autostart,
                     threshold: bufsize * 1024,
                     output_threshold,
@@ -139,73 +358,71 @@ impl actix::StreamHandler<codec::ServerMessage, io::Error> for Proto {
                     server_port,
                     server_ip,
                     control_ip: self.server_ip,
+                    use_ssl,
                     http_headers,
-                })
+                });
             }
 
             codec::ServerMessage::Gain(gain_left, gain_right) => {
                 info!("Got gain; Left: {}, Right: {}", gain_left, gain_right);
-                self.player
-                    .do_send(player::PlayerControl::Gain(gain_left, gain_right));
+                let _ = self.set_gain(gain_left, gain_right);
             }
 
             codec::ServerMessage::Enable(enable) => {
                 info!("Got enable: {}", enable);
-                self.player.do_send(player::PlayerControl::Enable(enable));
+                let _ = self.set_power(enable);
             }
 
             codec::ServerMessage::Stop => {
                 info!("Got stream stop");
-                self.player.do_send(player::PlayerControl::Stop);
+                // A player that has drained its output has already reported stopping
+                if self.started {
+                    self.emit_event(hooks::Event::Stop);
+                }
+                self.started = false;
+                self.streaming = false;
+                let _ = self.player.do_send(player::PlayerControl::Stop);
             }
 
             codec::ServerMessage::Pause(millis) => {
                 info!("Pause received with delay: {}", millis);
                 if millis == 0 {
-                    self.player.do_send(player::PlayerControl::Pause(false));
+                    let _ = self.player.do_send(player::PlayerControl::Pause(false));
                 } else {
-                    self.player.do_send(player::PlayerControl::Pause(true));
-                    let player_addr = self.player.clone();
-                    Arbiter::spawn(
-                        tokio_timer::Delay::new(
-                            Instant::now() + Duration::from_millis(millis as u64),
-                        )
-                        .and_then(move |_| {
-                            player_addr.do_send(player::PlayerControl::Unpause(true));
-                            future::ok(())
-                        })
-                        .map_err(|_| ()),
-                    )
+                    // Pause for the given interval then carry on as if nothing had happened
+                    let _ = self.player.do_send(player::PlayerControl::Pause(true));
+                    let at = self.clock_time() + millis as u64;
+                    let _ = self.player.do_send(player::PlayerControl::UnpauseAt {
+                        at: at,
+                        quiet: true,
+                    });
                 }
             }
 
             codec::ServerMessage::Unpause(millis) => {
-                info!("Unpause received with delay: {}", millis);
+                info!("Unpause received at jiffies: {}", millis);
                 if millis == 0 {
-                    self.player.do_send(player::PlayerControl::Unpause(false));
+                    let _ = self.player.do_send(player::PlayerControl::Unpause(false));
                 } else {
-                    let player_addr = self.player.clone();
-                    let delay = millis - self.jiffies();
-                    if delay > 0 {
-                        Arbiter::spawn(
-                            tokio_timer::Delay::new(
-                                Instant::now() + Duration::from_millis(delay as u64),
-                            )
-                            .and_then(move |_| {
-                                player_addr.do_send(player::PlayerControl::Unpause(true));
-                                future::ok(())
-                            })
-                            .map_err(|_| ()),
-                        )
+                    // Start at the given jiffies, which may be up to half the wrap ahead or behind
+                    let now = self.clock_time();
+                    let delay = millis.wrapping_sub(jiffies(now)) as i32;
+                    info!("Starting in {}ms", delay);
+                    let at = if delay < 0 {
+                        now.saturating_sub(-(delay as i64) as u64)
                     } else {
-                        player_addr.do_send(player::PlayerControl::Unpause(true));
-                    }
+                        now + delay as u64
+                    };
+                    let _ = self.player.do_send(player::PlayerControl::UnpauseAt {
+                        at: at,
+                        quiet: false,
+                    });
                 }
             }
 
             codec::ServerMessage::Skip(interval) => {
                 info!("Skip ahead by: {}", interval);
-                self.player.do_send(player::PlayerControl::Skip(interval));
+                let _ = self.player.do_send(player::PlayerControl::Skip(interval));
             }
 
             codec::ServerMessage::Setname(name) => {
@@ -218,6 +435,97 @@ impl actix::StreamHandler<codec::ServerMessage, io::Error> for Proto {
                     .write(codec::ClientMessage::Name(self.name.clone()));
             }
 
+            codec::ServerMessage::Vfdc(frame) => {
+                if let Some(display::Display::Text(ref mut display)) = self.display {
+                    display.update(&frame);
+                }
+            }
+
+            codec::ServerMessage::Grfe { offset, data } => {
+                if let Some(scroll) = self.scroll.take() {
+                    ctx.cancel_future(scroll);
+                }
+                if let Some(display::Display::Graphic(ref mut display)) = self.display {
+                    display.frame(offset as usize, &data);
+                }
+            }
+
+            codec::ServerMessage::Grfb(brightness) => {
+                if let Some(display::Display::Graphic(ref mut display)) = self.display {
+                    display.brightness(brightness);
+                }
+            }
+
+            codec::ServerMessage::Grfs {
+                direction,
+                pause,
+                speed,
+                by,
+                mode,
+                width,
+                offset,
+                data,
+            } => {
+                if let Some(display::Display::Graphic(ref mut display)) = self.display {
+                    let settings = display::ScrollSettings {
+                        direction: direction,
+                        pause: pause,
+                        speed: speed,
+                        by: by,
+                        mode: mode,
+                        width: width,
+                    };
+                    display.scroll_frame(settings, offset as usize, &data);
+                }
+            }
+
+            codec::ServerMessage::Grfg {
+                width,
+                data: background,
+            } => {
+                if let Some(scroll) = self.scroll.take() {
+                    ctx.cancel_future(scroll);
+                }
+                let speed = match self.display {
+                    Some(display::Display::Graphic(ref mut display)) => {
+                        display.scroll_start(width as usize, &background)
+                    }
+                    _ => None,
+                };
+                if let Some(speed) = speed {
+                    self.scroll = Some(ctx.run_interval(
+                        Duration::from_millis(speed as u64),
+                        |proto, ctx| {
+                            let running = match proto.display {
+                                Some(display::Display::Graphic(ref mut display)) => {
+                                    display.scroll_step()
+                                }
+                                _ => false,
+                            };
+                            if !running {
+                                if let Some(scroll) = proto.scroll.take() {
+                                    ctx.cancel_future(scroll);
+                                }
+                            }
+                        },
+                    ));
+                }
+            }
+
+            codec::ServerMessage::Visu { which, params } => {
+                self.visualiser = display::Visualiser::from_params(which, &params);
+                info!(
+                    "Setting visualiser: {}",
+                    self.visualiser.as_ref().map_or("off", |v| v.name())
+                );
+                self.visu_data = display::VisuData::default();
+                let _ = self.player.do_send(player::PlayerControl::Visualiser {
+                    level: self.visualiser.as_ref().map_or(false, |v| v.wants_levels()),
+                    bands: self.visualiser.as_ref().and_then(|v| v.bands()),
+                });
+                self.update_visualiser();
+            }
+
             codec::ServerMessage::Unknownsetd(id) => {
                 warn!("Unused SETD id: {}", id);
             }
@@ -242,13 +550,16 @@ impl actix::Handler<player::PlayerMessages> for Proto {
 
             player::PlayerMessages::Paused => {
                 self.framed.write(self.stat_data.make_stat_message("STMp"));
+                self.emit_event(hooks::Event::Pause);
             }
 
             player::PlayerMessages::Unpaused => {
                 self.framed.write(self.stat_data.make_stat_message("STMr"));
+                self.emit_event(hooks::Event::Resume);
             }
 
             player::PlayerMessages::Eos => {
+                self.streaming = false;
                 self.framed.write(self.stat_data.make_stat_message("STMd"));
             }
 
@@ -261,13 +572,18 @@ impl actix::Handler<player::PlayerMessages> for Proto {
                 self.framed.write(self.stat_data.make_stat_message("STMh"));
             }
 
-            player::PlayerMessages::Error => {
+            player::PlayerMessages::Error(error) => {
+                warn!("Reporting error: {}", error);
+                self.stat_data.error_code = error.error_code();
                 self.framed.write(self.stat_data.make_stat_message("STMn"));
+                self.emit_event(hooks::Event::Error(error.to_string()));
                 // self.player.do_send(player::PlayerControl::Stop);
             }
 
             player::PlayerMessages::Start => {
+                self.started = true;
                 self.framed.write(self.stat_data.make_stat_message("STMs"));
+                self.emit_event(hooks::Event::Play);
                 let proto = ctx.address().clone();
                 Arbiter::spawn(
                     tokio_timer::Delay::new(Instant::now() + Duration::from_millis(400))
@@ -281,13 +597,28 @@ impl actix::Handler<player::PlayerMessages> for Proto {
 
             player::PlayerMessages::Streamdata {
                 position,
+                position_time,
+                playing,
                 fullness,
                 output_buffer_fullness,
             } => {
+                self.position = position;
+                self.position_time = position_time;
+                self.playing = playing;
                 self.stat_data.elapsed_milliseconds = position as u32;
                 self.stat_data.elapsed_seconds = position as u32 / 1000;
                 self.stat_data.fullness = fullness;
                 self.stat_data.output_buffer_fullness = output_buffer_fullness;
+                if output_buffer_fullness > 0 {
+                    self.output_underrun = false;
+                }
+                self.update_mpris();
+                let due = self.buffers_published.elapsed()
+                    >= Duration::from_millis(MQTT_BUFFER_INTERVAL);
+                if self.options.mqtt_broker.is_some() && due {
+                    self.buffers_published = Instant::now();
+                    mqtt::Mqtt::from_registry().do_send(mqtt::Changed);
+                }
             }
 
             player::PlayerMessages::Bufsize(buf_size) => {
@@ -296,76 +627,505 @@ impl actix::Handler<player::PlayerMessages> for Proto {
             }
 
             player::PlayerMessages::Sendstatus => {
-                self.stat_data.jiffies = self.jiffies();
+                self.update_play_point();
                 self.framed.write(self.stat_data.make_stat_message("STMt"));
             }
 
             player::PlayerMessages::Overrun => {
                 if !self.autostart {
-                    self.player.do_send(player::PlayerControl::Pause(true));
+                    // The player is already paused waiting for the server to tell it when to start
                     self.framed.write(self.stat_data.make_stat_message("STMl"));
                     self.autostart = true;
                 }
             }
+
+            player::PlayerMessages::OutputUnderrun => {
+                // Only an underrun once playback has started, and only once until data arrives
+                if self.started && !self.output_underrun {
+                    self.output_underrun = true;
+                    self.output_underruns = self.output_underruns.wrapping_add(1);
+                    if self.streaming {
+                        warn!("Output buffer underrun, count: {}", self.output_underruns);
+                        self.update_play_point();
+                        self.framed.write(self.stat_data.make_stat_message("STMo"));
+                    } else {
+                        // Nothing more is coming so this is the end of playback
+                        info!("Output buffer drained");
+                        self.started = false;
+                        self.update_play_point();
+                        self.framed.write(self.stat_data.make_stat_message("STMu"));
+                        self.emit_event(hooks::Event::Stop);
+                    }
+                }
+            }
+
+            player::PlayerMessages::Metadata(track) => {
+                info!(
+                    "Now playing: {} - {}",
+                    track.artist.as_ref().map_or("unknown", |s| s.as_str()),
+                    track.title.as_ref().map_or("unknown", |s| s.as_str())
+                );
+                self.track = track;
+                self.emit_event(hooks::Event::Track(self.track.clone()));
+                self.update_mpris();
+            }
+
+            player::PlayerMessages::Levels(levels) => {
+                self.visu_data.levels = levels;
+                self.refresh_visualiser();
+            }
+
+            player::PlayerMessages::Spectrum(spectrum) => {
+                self.visu_data.spectrum = spectrum;
+                self.refresh_visualiser();
+            }
+
+            player::PlayerMessages::InputUnderrun => {
+                if self.started && self.streaming {
+                    self.input_underruns = self.input_underruns.wrapping_add(1);
+                    warn!("Input buffer underrun, count: {}", self.input_underruns);
+                }
+            }
+
+            player::PlayerMessages::OutputChanged(output_device) => {
+                info!("Switched output device");
+                if let Some(rate) = self.new_sample_rate.take() {
+                    self.options.max_sample_rate = Some(rate);
+                }
+                self.options.output_device = output_device;
+            }
+
+            player::PlayerMessages::OutputUnchanged => {
+                error!("Unable to switch output device, keeping the one in use");
+                self.new_sample_rate = None;
+            }
+        }
+    }
+}
+
+impl actix::Handler<api::GetStatus> for Proto {
+    type Result = actix::MessageResult<api::GetStatus>;
+
+    fn handle(&mut self, _msg: api::GetStatus, _ctx: &mut Context<Self>) -> Self::Result {
+        self.update_play_point();
+        actix::MessageResult(api::Status {
+            name: self.name.clone(),
+            server: self.server_ip.to_string(),
+            state: self.state(),
+            power: self.power,
+            volume: (self.volume * 100.0).round() as u8,
+            elapsed_milliseconds: self.stat_data.elapsed_milliseconds,
+            buffer_size: self.stat_data.buffer_size,
+            buffer_fullness: self.stat_data.fullness,
+            output_buffer_fullness: self.stat_data.output_buffer_fullness,
+            bytes_received: self.stat_data.bytes_received,
+            signal_strength: self.stat_data.sig_strength,
+            output_underruns: self.output_underruns,
+            input_underruns: self.input_underruns,
+            title: self.track.title.clone(),
+            artist: self.track.artist.clone(),
+            album: self.track.album.clone(),
+        })
+    }
+}
+
+impl actix::Handler<api::GetVisualiser> for Proto {
+    type Result = actix::MessageResult<api::GetVisualiser>;
+
+    fn handle(&mut self, _msg: api::GetVisualiser, _ctx: &mut Context<Self>) -> Self::Result {
+        actix::MessageResult(api::Visualiser {
+            mode: self.visualiser.as_ref().map_or("off", |v| v.name()),
+            levels: self.visu_data.levels.clone(),
+            spectrum: self.visu_data.spectrum.clone(),
+        })
+    }
+}
+
+// Commands from local tooling, which act on the player directly
+// Local control acts on the player straight away, the server hears of pauses and resumes
+// through the status it is sent as for any other
+impl actix::Handler<api::Command> for Proto {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: api::Command, ctx: &mut Context<Self>) -> Self::Result {
+        match msg {
+            api::Command::Pause | api::Command::Resume if !self.started => {
+                Err("Nothing is playing".to_owned())
+            }
+
+            api::Command::Pause => self.control(player::PlayerControl::Pause(false)),
+
+            api::Command::Resume => self.control(player::PlayerControl::Unpause(false)),
+
+            api::Command::Volume(level) => {
+                let gain = level.min(100) as f64 / 100.0;
+                self.set_gain(gain, gain)
+            }
+
+            api::Command::Power(power) => self.set_power(power),
+
+            api::Command::Output(output_device) => self.switch_output(output_device),
+
+            api::Command::Reconnect => {
+                info!("Reconnecting to server");
+                let server_ip = self.server_ip;
+                let server_port = self.server_port;
+                let sync_group_id = self.sync_group_id.clone();
+                let name = self.name.clone();
+                let options = Options {
+                    bufsize: Some(self.stat_data.buffer_size),
+                    ..self.options.clone()
+                };
+                // The old player lets go of the audio device before the new one is started
+                Arbiter::spawn(self.player.send(player::PlayerControl::Stop).then(move |_| {
+                    spawn_proto(server_ip, server_port, sync_group_id, &name, options);
+                    Ok(())
+                }));
+                ctx.stop();
+                Ok(())
+            }
         }
     }
 }
 
+// Read the output device again from its file, or open the same one again if there is none
+struct ReloadOutput;
+
+impl actix::Message for ReloadOutput {
+    type Result = ();
+}
+
+impl actix::Handler<ReloadOutput> for Proto {
+    type Result = ();
+
+    fn handle(&mut self, _msg: ReloadOutput, _ctx: &mut Context<Self>) {
+        let output_device = match self.options.output_config {
+            Some(ref path) => match player::AudioDevice::load(path) {
+                Ok(output_device) => output_device,
+                Err(e) => {
+                    error!("Unable to read output device from {}: {}", path.display(), e);
+                    return;
+                }
+            },
+            None => self.options.output_device.clone(),
+        };
+        let _ = self.switch_output(output_device);
+    }
+}
+
+// Button presses are passed on as they are, the server decides what they mean
+impl actix::Handler<input::Button> for Proto {
+    type Result = ();
+
+    fn handle(&mut self, msg: input::Button, _ctx: &mut Context<Self>) {
+        self.framed.write(codec::ClientMessage::Ir {
+            time: jiffies(self.clock_time()),
+            format: 0,
+            code: msg.0,
+        });
+    }
+}
+
+// Requests from the desktop, which the server must act on so that it stays in charge
+#[cfg(feature = "mpris")]
+impl actix::Handler<mpris::Request> for Proto {
+    type Result = ();
+
+    fn handle(&mut self, msg: mpris::Request, _ctx: &mut Context<Self>) {
+        let command = match msg {
+            mpris::Request::Play => String::from("play"),
+            mpris::Request::Pause => String::from("pause 1"),
+            mpris::Request::PlayPause => String::from("pause"),
+            mpris::Request::Stop => String::from("stop"),
+            mpris::Request::Next => String::from("playlist index +1"),
+            mpris::Request::Previous => String::from("playlist index -1"),
+            mpris::Request::Volume(volume) => {
+                format!("mixer volume {}", (volume * 100.0).round() as u8)
+            }
+            // MPRIS positions are in microseconds, the server's in seconds
+            mpris::Request::Seek(offset) => format!("time {:+.3}", offset as f64 / 1_000_000.0),
+            mpris::Request::SetPosition(position) => {
+                format!("time {:.3}", position as f64 / 1_000_000.0)
+            }
+        };
+        info!("MPRIS request: {}", command);
+        self.server_command(&command);
+    }
+}
+
 impl Proto {
-    fn jiffies(&self) -> u32 {
-        let dur = self.creation_time.elapsed();
-        ((dur.as_secs() * 1000 + dur.subsec_millis() as u64) % (::std::u32::MAX as u64 + 1)) as u32
+    fn state(&self) -> &'static str {
+        if self.playing {
+            "play"
+        } else if self.started {
+            "pause"
+        } else {
+            "stop"
+        }
+    }
+
+    fn update_visualiser(&mut self) {
+        if let Some(display::Display::Graphic(ref mut display)) = self.display {
+            display.visualise(self.visualiser.clone(), &self.visu_data);
+        }
+    }
+
+    fn refresh_visualiser(&mut self) {
+        if let Some(display::Display::Graphic(ref mut display)) = self.display {
+            display.visu_data(&self.visu_data);
+        }
+    }
+
+    #[cfg(feature = "mpris")]
+    fn update_mpris(&self) {
+        if self.options.mpris {
+            mpris::Mpris::from_registry().do_send(mpris::Update {
+                status: match self.state() {
+                    "play" => "Playing",
+                    "pause" => "Paused",
+                    _ => "Stopped",
+                },
+                volume: self.volume,
+                position: self.position,
+                track: self.track.clone(),
+            });
+        }
+    }
+
+    #[cfg(not(feature = "mpris"))]
+    fn update_mpris(&self) {}
+
+    fn control(&self, control: player::PlayerControl) -> Result<(), String> {
+        self.player
+            .do_send(control)
+            .map_err(|_| "The player has stopped".to_owned())
+    }
+
+    fn set_gain(&mut self, gain_left: f64, gain_right: f64) -> Result<(), String> {
+        self.control(player::PlayerControl::Gain(gain_left, gain_right))?;
+        self.volume = gain_left.max(gain_right).min(1.0);
+        self.emit_event(hooks::Event::Volume((self.volume * 100.0).round() as u8));
+        self.update_mpris();
+        Ok(())
+    }
+
+    fn set_power(&mut self, enable: bool) -> Result<(), String> {
+        self.control(player::PlayerControl::Enable(enable))?;
+        self.power = enable;
+        self.emit_event(if enable {
+            hooks::Event::PowerOn
+        } else {
+            hooks::Event::PowerOff
+        });
+        Ok(())
+    }
+
+    // Commands go to the server's CLI as: <player id> <command>
+    #[cfg(feature = "mpris")]
+    fn server_command(&self, command: &str) {
+        use tokio_io::io::write_all;
+
+        let addr = SocketAddr::new(IpAddr::V4(self.server_ip), self.options.cli_port);
+        let line = format!(
+            "{} {}\n",
+            get_mac().to_string().to_lowercase().replace(":", "%3A"),
+            command
+        );
+        Arbiter::spawn(
+            TcpStream::connect(&addr)
+                .and_then(move |stream| write_all(stream, line.into_bytes()))
+                .map(|_| ())
+                .map_err(|e| warn!("Unable to send command to server: {}", e)),
+        );
+    }
+
+    // Later connections to a server use the new device too, once the player has switched to it
+    fn switch_output(&mut self, output_device: player::AudioDevice) -> Result<(), String> {
+        if let Err(e) = self.options.backend.check(&output_device) {
+            error!("Unable to use output device: {}", e);
+            return Err(e);
+        }
+        // Whilst the old device is still open the new one is free to be asked, unless they are
+        // the same device, which is no different to before
+        self.new_sample_rate = probe_sample_rate(&self.options.backend, &output_device);
+        self.control(player::PlayerControl::Output(output_device))
+    }
+
+    fn clock_time(&self) -> u64 {
+        self.options.backend.clock_time()
+    }
+
+    fn emit_event(&self, event: hooks::Event) {
+        if let Some(ref events) = self.options.events {
+            let _ = events.unbounded_send(event.clone());
+        }
+        if self.options.mqtt_broker.is_some() {
+            mqtt::Mqtt::from_registry().do_send(mqtt::Changed);
+        }
+
+        match event {
+            // Only changes of state are worth running a command for
+            hooks::Event::Volume(_) | hooks::Event::Track(_) => (),
+            _ => {
+                if let Some(ref command) = self.options.event_hook {
+                    hooks::run(command, event, &self.name, &self.track);
+                }
+            }
+        }
+    }
+
+    fn update_signal_strength(&mut self) {
+        let wireless = fs::read_to_string(WIRELESS_PATH).ok();
+        self.stat_data.sig_strength = signal_strength(
+            wireless.as_ref().map(|w| w.as_str()),
+            self.interface.as_ref().map(|i| i.as_str()),
+        );
+    }
+
+    // Pair the jiffies with the elapsed time at that instant, as the server relies on both
+    fn update_play_point(&mut self) {
+        let now = self.clock_time();
+        self.stat_data.jiffies = jiffies(now);
+        if self.playing {
+            let elapsed = self.position + now.saturating_sub(self.position_time);
+            self.stat_data.elapsed_milliseconds = elapsed as u32;
+            self.stat_data.elapsed_seconds = (elapsed / 1000) as u32;
+        }
     }
 }
 
+// Jiffies are the milliseconds of the clock that drives the audio output, wrapped to 32 bits
+fn jiffies(clock_time: u64) -> u32 {
+    (clock_time % (::std::u32::MAX as u64 + 1)) as u32
+}
+
 pub fn run(
     server_ip: Ipv4Addr,
+    server_port: u16,
     sync_group: Option<String>,
     name: &str,
-    bufsize: Option<u32>,
-    output_device: player::AudioDevice,
+    options: Options,
 ) -> std::io::Result<()> {
     let sys = System::new("Storm");
-    spawn_proto(server_ip, sync_group, name, bufsize, output_device);
+    start(server_ip, server_port, sync_group, name, options);
     spawn_signal_handler();
     sys.run()
 }
 
+// Start everything in the current system, which is left for the caller to run
+pub fn start(
+    server_ip: Ipv4Addr,
+    server_port: u16,
+    sync_group: Option<String>,
+    name: &str,
+    mut options: Options,
+) {
+    // Only asked the once, as on reconnecting the device may still be held by the old player
+    options.max_sample_rate = probe_sample_rate(&options.backend, &options.output_device);
+    if let Some(port) = options.api_port {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+        api::Api::from_registry().do_send(api::Listen(addr));
+    }
+    if let Some(ref device) = options.input {
+        input::Input::from_registry().do_send(input::Start {
+            device: device.clone(),
+            keymap: options.keymap.clone(),
+        });
+    }
+    if let Some(ref broker) = options.mqtt_broker {
+        mqtt::Mqtt::from_registry().do_send(mqtt::Start {
+            broker: broker.clone(),
+            player_id: get_mac().to_string().to_lowercase().replace(":", ""),
+        });
+    }
+    #[cfg(feature = "mpris")]
+    {
+        if options.mpris {
+            mpris::Mpris::from_registry().do_send(mpris::Start(name.to_owned()));
+        }
+    }
+    spawn_proto(server_ip, server_port, sync_group, name, options);
+}
+
+fn probe_sample_rate(
+    backend: &Arc<dyn backend::Backend>,
+    output_device: &player::AudioDevice,
+) -> Option<u32> {
+    let rate = backend.max_sample_rate(output_device);
+    match rate {
+        Some(rate) => info!("Maximum sample rate: {}", rate),
+        None => warn!("Unable to determine maximum sample rate of output device"),
+    }
+    rate
+}
+
 fn spawn_proto(
     server_ip: Ipv4Addr,
+    server_port: u16,
     sync_group: Option<String>,
     name: &str,
-    bufsize: Option<u32>,
-    output_device: player::AudioDevice,
+    options: Options,
 ) {
     let name = name.to_owned();
-    let addr = SocketAddr::new(IpAddr::V4(server_ip), 3483);
+    let addr = SocketAddr::new(IpAddr::V4(server_ip), server_port);
     Arbiter::spawn(
         TcpStream::connect(&addr)
             .and_then(move |stream| {
+                let interface = stream
+                    .local_addr()
+                    .ok()
+                    .and_then(|addr| get_interface(addr.ip()));
                 Proto::create(move |ctx| {
-                    let player = player::Player::new(ctx.address(), output_device.clone());
+                    let player = options.backend.start(
+                        ctx.address().recipient(),
+                        &options.output_device,
+                        options.device_release,
+                    );
                     let (r, w) = stream.split();
                     ctx.add_stream(FramedRead::new(r, codec::SlimCodec));
                     let mut proto = Proto {
                         sync_group_id: sync_group,
-                        creation_time: Instant::now(),
+                        position: 0,
+                        position_time: 0,
+                        playing: false,
+                        started: false,
+                        streaming: false,
+                        output_underrun: false,
+                        output_underruns: 0,
+                        new_sample_rate: None,
+                        input_underruns: 0,
                         stat_data: codec::StatData::default(),
                         server_ip: server_ip,
+                        server_port: server_port,
+                        interface: interface,
                         name: name,
-                        output_device: output_device,
+                        options: options.clone(),
+                        track: player::TrackInfo::default(),
+                        power: false,
+                        volume: 1.0,
                         autostart: true,
-                        player: player.start(),
+                        display: options.profile.display.and_then(|kind| {
+                            options
+                                .display
+                                .clone()
+                                .and_then(|output| display::Display::new(kind, output))
+                        }),
+                        scroll: None,
+                        visualiser: None,
+                        visu_data: display::VisuData::default(),
+                        buffers_published: Instant::now(),
+                        player: player,
                         framed: actix::io::FramedWrite::new(w, codec::SlimCodec, ctx),
                     };
-                    proto.stat_data.buffer_size = bufsize.unwrap_or(0);
+                    proto.stat_data.buffer_size = options.bufsize.unwrap_or(0);
                     proto
                 });
                 future::ok(())
             })
             .map_err(|e| {
+                // Leave it to whoever is running the system to decide what happens next
                 error!("Cannot connect to server: {}", e);
-                ::std::process::exit(2)
+                System::current().stop_with_code(2)
             }),
     );
 }
@@ -381,6 +1141,23 @@ fn spawn_signal_handler() {
                 future::ok(())
             }),
     );
+
+    Arbiter::spawn(
+        Signal::new(SIGHUP)
+            .flatten_stream()
+            .for_each(|_| {
+                info!("Received HUP signal, reloading the output device");
+                api::Api::from_registry()
+                    .send(api::GetProto)
+                    .map(|proto| {
+                        if let Some(proto) = proto {
+                            proto.do_send(ReloadOutput);
+                        }
+                    })
+                    .then(|_| Ok(()))
+            })
+            .map_err(|e| warn!("Unable to handle HUP signals: {}", e)),
+    );
 }
 
 struct Discover;
@@ -442,43 +1219,648 @@ fn get_mac() -> mac_address::MacAddress {
     }
 }
 
-fn get_decode_caps() -> Vec<String> {
-    let ffmpeg = match Command::new("/usr/bin/ffmpeg").arg("-decoders").output() {
-        Ok(output) => output.stdout,
-        Err(_) => {
-            warn!("No decoders detected");
-            Vec::new()
+fn get_interface(ip: IpAddr) -> Option<String> {
+    get_if_addrs::get_if_addrs()
+        .ok()?
+        .into_iter()
+        .find(|interface| interface.ip() == ip)
+        .map(|interface| interface.name)
+}
+
+// Signal quality as a percentage from the contents of /proc/net/wireless, e.g:
+// Inter-| sta-|   Quality        |   Discarded packets               | Missed | WE
+//  face | tus | link level noise |  nwid  crypt   frag  retry   misc | beacon | 22
+//  wlan0: 0000   70.  -40.  -256        0      0      0      0      0        0
+// An interface that is not listed, or no list at all, is wired. With no interface known
+// there is nothing to report
+fn signal_strength(wireless: Option<&str>, interface: Option<&str>) -> u16 {
+    // Most drivers scale link quality to this
+    const MAX_QUALITY: f64 = 70.0;
+
+    let (wireless, interface) = match (wireless, interface) {
+        (_, None) => return 0,
+        (None, Some(_)) => return WIRED_SIGNAL_STRENGTH,
+        (Some(wireless), Some(interface)) => (wireless, interface),
+    };
+
+    wireless
+        .lines()
+        .skip(2)
+        .filter_map(|line| {
+            let mut fields = line.splitn(2, ':');
+            match (fields.next(), fields.next()) {
+                (Some(name), Some(stats)) if name.trim() == interface => Some(stats.to_owned()),
+                _ => None,
+            }
+        })
+        .next()
+        .and_then(|stats| {
+            stats
+                .split_whitespace()
+                .nth(1)
+                .and_then(|link| link.trim_end_matches('.').parse::<f64>().ok())
+        })
+        .map(|link| (link * 100.0 / MAX_QUALITY).min(100.0).max(0.0) as u16)
+        .unwrap_or(WIRED_SIGNAL_STRENGTH)
+}
+
+// The HELO channel list has a bit for each 2.4GHz channel the player can use, starting at
+// channel 1. We give the one the interface is on, asked from the wireless extensions that
+// also fill in /proc/net/wireless. Wired interfaces, and anything on 5GHz, have none
+fn wlan_channel_list(interface: &str) -> u16 {
+    // The start of struct iwreq, whose union holds a struct iw_freq for this request
+    #[repr(C)]
+    struct Request {
+        name: [u8; libc::IFNAMSIZ],
+        m: i32,
+        e: i16,
+        index: u8,
+        flags: u8,
+        padding: [u8; 8],
+    }
+    const SIOCGIWFREQ: libc::c_ulong = 0x8b05;
+
+    let mut request = Request {
+        name: [0; libc::IFNAMSIZ],
+        m: 0,
+        e: 0,
+        index: 0,
+        flags: 0,
+        padding: [0; 8],
+    };
+    if interface.len() >= request.name.len() {
+        return 0;
+    }
+    request.name[..interface.len()].copy_from_slice(interface.as_bytes());
+
+    let found = unsafe {
+        let socket = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0);
+        if socket < 0 {
+            return 0;
+        }
+        let result = libc::ioctl(socket, SIOCGIWFREQ as _, &mut request as *mut Request);
+        libc::close(socket);
+        result >= 0
+    };
+
+    if found {
+        channel_bit(request.m, request.e)
+    } else {
+        0
+    }
+}
+
+// Drivers give either a frequency, as m * 10^e Hz, or a small channel number with e of 0
+fn channel_bit(m: i32, e: i16) -> u16 {
+    let channel = if e == 0 && m < 1000 {
+        m as i64
+    } else {
+        match (m as f64 * 10f64.powi(e as i32) / 1e6).round() as i64 {
+            2484 => 14,
+            mhz @ 2412..=2472 => (mhz - 2407) / 5,
+            _ => 0,
         }
     };
 
-    let decoders = vec![
-        ("alac", "alc"),
-        ("wma", "wma"),
-        ("wmap", "wmap"),
-        ("wmal", "wmal"),
-        ("flac", "flc"),
-        ("aac", "aac"),
-        ("vorbis", "ogg"),
-        ("pcm", "pcm"),
-        ("mp3", "mp3"),
-    ];
-
-    let sets: Vec<String> = decoders
-        .iter()
-        .map(|s| [r"^.A.....\s+", s.0].join(""))
-        .collect();
-
-    let matches: Vec<_> = match RegexSetBuilder::new(&sets).multi_line(true).build() {
-        Ok(set) => set,
-        Err(_) => return Vec::new(),
-    }
-    .matches(std::str::from_utf8(&ffmpeg).unwrap())
-    .into_iter()
-    .collect();
-
-    let mut caps = Vec::new();
-    matches
-        .iter()
-        .for_each(|cap| caps.push(decoders[*cap].1.to_owned()));
-    caps
+    match channel {
+        1..=14 => 1u16 << (channel - 1),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{Backend, Recorder};
+    use bytes::{Buf, BufMut, IntoBuf};
+    use futures::Async;
+    use player::{PlayerControl, PlayerError, PlayerMessages};
+    use testing;
+
+    use std::io::{Read, Write};
+    use std::net::{self, TcpListener};
+    use std::thread;
+
+    // Just enough of a server to talk to one player
+    struct FakeServer {
+        stream: net::TcpStream,
+        capabilities: String,
+        // Local control of the player connected most recently
+        api: actix::Addr<api::Api>,
+    }
+
+    // What a player said in a STAT message
+    struct Stat {
+        jiffies: u32,
+        elapsed_milliseconds: u32,
+        timestamp: u32,
+        error_code: u16,
+    }
+
+    impl FakeServer {
+        fn accept(listener: &TcpListener, api: actix::Addr<api::Api>) -> Self {
+            let (stream, _) = listener.accept().unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut server = FakeServer {
+                stream: stream,
+                capabilities: String::new(),
+                api: api,
+            };
+
+            let (command, helo) = server.receive();
+            assert_eq!(command, "HELO");
+            // After the device id, revision, MAC, UUID, channel list and bytes received
+            server.capabilities = String::from_utf8_lossy(&helo[34..]).into_owned();
+            server
+        }
+
+        fn send(&mut self, command: &str, payload: &[u8]) {
+            let mut frame = Vec::new();
+            frame.put_u16_be((command.len() + payload.len()) as u16);
+            frame.put(command.as_bytes());
+            frame.put(payload);
+            self.stream.write_all(&frame).unwrap();
+        }
+
+        fn receive(&mut self) -> (String, Vec<u8>) {
+            let mut head = [0u8; 8];
+            self.stream.read_exact(&mut head).unwrap();
+            let mut payload = vec![0u8; head[4..].into_buf().get_u32_be() as usize];
+            self.stream.read_exact(&mut payload).unwrap();
+            (String::from_utf8_lossy(&head[..4]).into_owned(), payload)
+        }
+
+        // Wait for the player to send a STAT with the given event, ignoring anything else
+        fn stat(&mut self, event: &str) -> Stat {
+            loop {
+                let (command, payload) = self.receive();
+                if command == "STAT" && &payload[..4] == event.as_bytes() {
+                    let field = |at: usize| payload[at..at + 4].into_buf().get_u32_be();
+                    return Stat {
+                        jiffies: field(25),
+                        elapsed_milliseconds: field(43),
+                        timestamp: field(47),
+                        error_code: payload[51..53].into_buf().get_u16_be(),
+                    };
+                }
+            }
+        }
+
+        fn strm(&mut self, command: char, autostart: char, timestamp: u32, http_headers: &str) {
+            let mut payload = Vec::new();
+            payload.put_u8(command as u8);
+            payload.put_u8(autostart as u8);
+            // Format, sample size, rate, channels, endianness
+            payload.put(&b"m????"[..]);
+            // Input threshold in KB, then S/PDIF, transition period and type, and flags
+            payload.put_u8(255);
+            payload.put(&[0u8; 4][..]);
+            // Output threshold in tenths of a second, then one reserved
+            payload.put_u8(10);
+            payload.put_u8(0);
+            payload.put_u32_be(timestamp);
+            payload.put_u16_be(9000);
+            payload.put_u32_be(0);
+            payload.put(http_headers.as_bytes());
+            self.send("strm", &payload);
+        }
+
+        fn audg(&mut self, left: u32, right: u32) {
+            let mut payload = Vec::new();
+            payload.put(&[0u8; 10][..]);
+            payload.put_u32_be(left);
+            payload.put_u32_be(right);
+            self.send("audg", &payload);
+        }
+
+        // As the control API and applications embedding the player send them
+        fn command(&self, command: api::Command) -> Result<(), String> {
+            let proto = self.api.send(api::GetProto).wait().unwrap().unwrap();
+            proto.send(command).wait().unwrap()
+        }
+    }
+
+    // Connect a player for each recorder to a server that runs the test, then stops everything
+    fn with_players<F>(recorders: &[Recorder], test: F)
+    where
+        F: FnOnce(Vec<FakeServer>) + Send + 'static,
+    {
+        let options = recorders
+            .iter()
+            .map(|recorder| Options {
+                backend: Arc::new(recorder.clone()),
+                ..Options::default()
+            })
+            .collect();
+        with_options(options, test);
+    }
+
+    fn with_options<F>(options: Vec<Options>, test: F)
+    where
+        F: FnOnce(Vec<FakeServer>) + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let sys = System::new("test");
+        let count = options.len();
+        for (n, options) in options.into_iter().enumerate() {
+            spawn_proto(Ipv4Addr::LOCALHOST, port, None, &format!("Player {}", n), options);
+        }
+
+        let api = api::Api::from_registry();
+        testing::run(sys, move || {
+            // Players connect in any order, so put them back in the order they were started
+            let mut servers: Vec<FakeServer> =
+                (0..count).map(|_| FakeServer::accept(&listener, api.clone())).collect();
+            servers.sort_by(|a, b| a.capabilities.cmp(&b.capabilities));
+            test(servers)
+        });
+    }
+
+    fn last_control(recorder: &Recorder) -> Option<PlayerControl> {
+        recorder.controls().last().cloned()
+    }
+
+    #[test]
+    fn stream_is_started() {
+        let recorder = Recorder::new();
+        let player = recorder.clone();
+        with_players(&[recorder], move |mut servers| {
+            let server = &mut servers[0];
+            server.strm('s', '1', 0, "GET /stream.flac HTTP/1.0\r\n\r\n");
+            server.stat("STMc");
+            server.stat("STMe");
+            server.stat("STMs");
+
+            match last_control(&player) {
+                Some(PlayerControl::Stream {
+                    autostart,
+                    threshold,
+                    output_threshold,
+                    server_port,
+                    control_ip,
+                    http_headers,
+                    ..
+                }) => {
+                    assert!(autostart);
+                    assert_eq!(threshold, 255 * 1024);
+                    assert_eq!(output_threshold, 1_000_000_000);
+                    assert_eq!(server_port, 9000);
+                    assert_eq!(control_ip, Ipv4Addr::LOCALHOST);
+                    assert_eq!(http_headers, "GET /stream.flac HTTP/1.0\r\n\r\n");
+                }
+                control => panic!("Expected a stream, got: {:?}", control),
+            }
+        });
+    }
+
+    #[test]
+    fn gain_is_passed_on() {
+        let recorder = Recorder::new();
+        let player = recorder.clone();
+        with_players(&[recorder], move |mut servers| {
+            servers[0].audg(0x8000, 0x10000);
+            wait_for(|| match last_control(&player) {
+                Some(PlayerControl::Gain(left, right)) => left == 0.5 && right == 1.0,
+                _ => false,
+            });
+        });
+    }
+
+    #[test]
+    fn local_volume_and_power_go_to_the_player() {
+        let recorder = Recorder::new();
+        let player = recorder.clone();
+        with_players(&[recorder], move |servers| {
+            assert_eq!(servers[0].command(api::Command::Volume(50)), Ok(()));
+            wait_for(|| match last_control(&player) {
+                Some(PlayerControl::Gain(left, right)) => left == 0.5 && right == 0.5,
+                _ => false,
+            });
+
+            assert_eq!(servers[0].command(api::Command::Power(false)), Ok(()));
+            wait_for(|| match last_control(&player) {
+                Some(PlayerControl::Enable(false)) => true,
+                _ => false,
+            });
+        });
+    }
+
+    #[test]
+    fn local_pause_is_reported_to_the_server() {
+        let recorder = Recorder::new();
+        let player = recorder.clone();
+        with_players(&[recorder], move |mut servers| {
+            assert!(servers[0].command(api::Command::Pause).is_err());
+
+            servers[0].strm('s', '1', 0, "GET /stream.flac HTTP/1.0\r\n\r\n");
+            servers[0].stat("STMs");
+            assert_eq!(servers[0].command(api::Command::Pause), Ok(()));
+            servers[0].stat("STMp");
+            match last_control(&player) {
+                Some(PlayerControl::Pause(false)) => (),
+                control => panic!("Expected a pause, got: {:?}", control),
+            }
+
+            assert_eq!(servers[0].command(api::Command::Resume), Ok(()));
+            servers[0].stat("STMr");
+        });
+    }
+
+    #[test]
+    fn pause_and_unpause_are_reported() {
+        let recorder = Recorder::new();
+        let player = recorder.clone();
+        with_players(&[recorder], move |mut servers| {
+            let server = &mut servers[0];
+            server.strm('p', '0', 0, "");
+            server.stat("STMp");
+            match last_control(&player) {
+                Some(PlayerControl::Pause(false)) => (),
+                control => panic!("Expected a pause, got: {:?}", control),
+            }
+
+            server.strm('u', '0', 0, "");
+            server.stat("STMr");
+            match last_control(&player) {
+                Some(PlayerControl::Unpause(false)) => (),
+                control => panic!("Expected an unpause, got: {:?}", control),
+            }
+        });
+    }
+
+    #[test]
+    fn pause_for_an_interval() {
+        let recorder = Recorder::new();
+        let player = recorder.clone();
+        recorder.advance(1000);
+        with_players(&[recorder], move |mut servers| {
+            servers[0].strm('p', '0', 500, "");
+            wait_for(|| player.controls().len() == 2);
+
+            let controls = player.controls();
+            match (&controls[0], &controls[1]) {
+                (
+                    PlayerControl::Pause(true),
+                    PlayerControl::UnpauseAt {
+                        at: 1500,
+                        quiet: true,
+                    },
+                ) => (),
+                controls => panic!("Expected a quiet pause and unpause, got: {:?}", controls),
+            }
+        });
+    }
+
+    #[test]
+    fn unpause_at_jiffies() {
+        let recorder = Recorder::new();
+        let player = recorder.clone();
+        recorder.advance(1000);
+        with_players(&[recorder], move |mut servers| {
+            let server = &mut servers[0];
+            server.strm('u', '0', 1200, "");
+            server.stat("STMr");
+            match last_control(&player) {
+                Some(PlayerControl::UnpauseAt {
+                    at: 1200,
+                    quiet: false,
+                }) => (),
+                control => panic!("Expected an unpause at 1200, got: {:?}", control),
+            }
+
+            // Jiffies wrap, the clock does not
+            player.advance(::std::u32::MAX as u64 - 1100);
+            server.strm('u', '0', 100, "");
+            server.stat("STMr");
+            match last_control(&player) {
+                Some(PlayerControl::UnpauseAt { at, quiet: false }) => {
+                    assert_eq!(at, ::std::u32::MAX as u64 + 101)
+                }
+                control => panic!("Expected an unpause after the wrap, got: {:?}", control),
+            }
+        });
+    }
+
+    #[test]
+    fn skip_is_passed_on() {
+        let recorder = Recorder::new();
+        let player = recorder.clone();
+        with_players(&[recorder], move |mut servers| {
+            servers[0].strm('a', '0', 250, "");
+            wait_for(|| match last_control(&player) {
+                Some(PlayerControl::Skip(250)) => true,
+                _ => false,
+            });
+        });
+    }
+
+    #[test]
+    fn stop_is_flushed() {
+        let recorder = Recorder::new();
+        let player = recorder.clone();
+        with_players(&[recorder], move |mut servers| {
+            servers[0].strm('q', '0', 0, "");
+            servers[0].stat("STMf");
+            match last_control(&player) {
+                Some(PlayerControl::Stop) => (),
+                control => panic!("Expected a stop, got: {:?}", control),
+            }
+        });
+    }
+
+    #[test]
+    fn drained_player_stops_once() {
+        let recorder = Recorder::new();
+        let player = recorder.clone();
+        let (sender, mut events) = mpsc::unbounded();
+        let options = Options {
+            backend: Arc::new(recorder),
+            events: Some(sender),
+            ..Options::default()
+        };
+        with_options(vec![options], move |mut servers| {
+            servers[0].strm('s', '1', 0, "GET /stream.flac HTTP/1.0\r\n\r\n");
+            servers[0].stat("STMs");
+            player.send(PlayerMessages::Eos);
+            servers[0].stat("STMd");
+            player.send(PlayerMessages::OutputUnderrun);
+            servers[0].stat("STMu");
+
+            // The server then stops the stream it knows has ended
+            servers[0].strm('q', '0', 0, "");
+            servers[0].stat("STMf");
+        });
+
+        // Take whatever events were sent without waiting for more
+        let stops = future::lazy(move || {
+            let mut stops = 0;
+            while let Ok(Async::Ready(Some(event))) = events.poll() {
+                if let hooks::Event::Stop = event {
+                    stops += 1;
+                }
+            }
+            Ok::<_, ()>(stops)
+        })
+        .wait()
+        .unwrap();
+        assert_eq!(stops, 1);
+    }
+
+    #[test]
+    fn end_of_stream_is_reported() {
+        let recorder = Recorder::new();
+        let player = recorder.clone();
+        with_players(&[recorder], move |mut servers| {
+            player.send(PlayerMessages::Eos);
+            servers[0].stat("STMd");
+        });
+    }
+
+    #[test]
+    fn error_is_reported() {
+        let recorder = Recorder::new();
+        let player = recorder.clone();
+        with_players(&[recorder], move |mut servers| {
+            player.send(PlayerMessages::Error(PlayerError::DecoderNotFound));
+            assert_eq!(servers[0].stat("STMn").error_code, 3);
+            player.send(PlayerMessages::Error(PlayerError::Output));
+            assert_eq!(servers[0].stat("STMn").error_code, 5);
+
+            // A new stream starts without an error
+            servers[0].strm('s', '1', 0, "GET /stream.flac HTTP/1.0\r\n\r\n");
+            assert_eq!(servers[0].stat("STMc").error_code, 0);
+        });
+    }
+
+    #[test]
+    fn status_has_jiffies_and_timestamp() {
+        let recorder = Recorder::new();
+        recorder.advance(5000);
+        with_players(&[recorder], move |mut servers| {
+            servers[0].strm('t', '0', 0x1234_5678, "");
+            let stat = servers[0].stat("STMt");
+            assert_eq!(stat.jiffies, 5000);
+            assert_eq!(stat.timestamp, 0x1234_5678);
+            assert_eq!(stat.error_code, 0);
+        });
+    }
+
+    // Two players whose clocks are far apart are each told in their own jiffies when to start,
+    // then report how far they have got at different moments. Asked at the same moment, they
+    // must give the same play point, before and after skipping ahead
+    #[test]
+    fn players_stay_in_sync() {
+        let first = Recorder::new();
+        let second = Recorder::new();
+        first.advance(1_000);
+        second.advance(7_654_321);
+        let players = vec![first.clone(), second.clone()];
+        with_players(&[first, second], move |mut servers| {
+            let mut starts = Vec::new();
+            for (server, player) in servers.iter_mut().zip(players.iter()) {
+                server.strm('t', '0', 0, "");
+                let jiffies = server.stat("STMt").jiffies;
+                server.strm('u', '0', jiffies.wrapping_add(500), "");
+                server.stat("STMr");
+                match last_control(player) {
+                    Some(PlayerControl::UnpauseAt { at, .. }) => {
+                        assert_eq!(at, player.clock_time() + 500);
+                        starts.push(at);
+                    }
+                    control => panic!("Expected a timed unpause, got: {:?}", control),
+                }
+            }
+
+            let mut skipped = 0;
+            for round in 0..10 {
+                if round == 5 {
+                    for (server, player) in servers.iter_mut().zip(players.iter()) {
+                        server.strm('a', '0', 250, "");
+                        wait_for(|| match last_control(player) {
+                            Some(PlayerControl::Skip(250)) => true,
+                            _ => false,
+                        });
+                    }
+                    skipped += 250;
+                }
+
+                let mut play_points = Vec::new();
+                for (n, (server, player)) in servers.iter_mut().zip(players.iter()).enumerate() {
+                    player.advance(1_000);
+                    let now = player.clock_time();
+                    // Each last said where it was a different time ago
+                    let lag = if n == 0 { 10 * round } else { 90 - 10 * round };
+                    player.send(PlayerMessages::Streamdata {
+                        position: now - lag - starts[n] + skipped,
+                        position_time: now - lag,
+                        playing: true,
+                        fullness: 0,
+                        output_buffer_fullness: 0,
+                    });
+                    // Give the player's mailbox a moment, as the request comes another way
+                    thread::sleep(Duration::from_millis(20));
+
+                    server.strm('t', '0', 0, "");
+                    let stat = server.stat("STMt");
+                    assert_eq!(stat.jiffies as u64, now);
+                    play_points.push(stat.elapsed_milliseconds);
+                }
+
+                let expected = (round + 1) * 1_000 - 500 + skipped;
+                assert_eq!(play_points, vec![expected as u32, expected as u32]);
+            }
+        });
+    }
+
+    const WIRELESS: &str = "\
+Inter-| sta-|   Quality        |   Discarded packets               | Missed | WE
+ face | tus | link level noise |  nwid  crypt   frag  retry   misc | beacon | 22
+ wlan0: 0000   35.  -62.  -256        0      0      0      0      0        0
+";
+
+    #[test]
+    fn wireless_signal_strength() {
+        assert_eq!(signal_strength(Some(WIRELESS), Some("wlan0")), 50);
+        let strong = WIRELESS.replace("35.", "77.");
+        assert_eq!(signal_strength(Some(&strong), Some("wlan0")), 100);
+    }
+
+    #[test]
+    fn wired_signal_strength() {
+        // Listed wireless interfaces are not the one in use
+        assert_eq!(
+            signal_strength(Some(WIRELESS), Some("eth0")),
+            WIRED_SIGNAL_STRENGTH
+        );
+        // Only the headings, as on a host with no wireless interfaces
+        let headings: String = WIRELESS.lines().take(2).collect::<Vec<_>>().join("\n");
+        assert_eq!(
+            signal_strength(Some(&headings), Some("eth0")),
+            WIRED_SIGNAL_STRENGTH
+        );
+        // No wireless extensions at all
+        assert_eq!(signal_strength(None, Some("eth0")), WIRED_SIGNAL_STRENGTH);
+    }
+
+    #[test]
+    fn no_interface_signal_strength() {
+        assert_eq!(signal_strength(Some(WIRELESS), None), 0);
+        assert_eq!(signal_strength(None, None), 0);
+    }
+
+    #[test]
+    fn channels_from_frequencies() {
+        assert_eq!(channel_bit(2412, 6), 0x0001);
+        assert_eq!(channel_bit(243_700_000, 1), 0x0020);
+        assert_eq!(channel_bit(24_620_000, 2), 0x0400);
+        assert_eq!(channel_bit(2_472_000, 3), 0x1000);
+        assert_eq!(channel_bit(2484, 6), 0x2000);
+        assert_eq!(channel_bit(11, 0), 0x0400);
+        assert_eq!(channel_bit(5180, 6), 0);
+        assert_eq!(channel_bit(0, 0), 0);
+    }
 }
@@ -0,0 +1,36 @@
+// Helpers for the tests that need actors running
+
+use actix;
+
+use std::panic;
+use std::thread;
+use std::time::Duration;
+
+// Run the test on a thread of its own whilst the system runs the actors, then stop the system
+// whether or not the test passes, so a failure is reported rather than hanging
+pub fn run<F>(sys: actix::SystemRunner, test: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    let system = actix::System::current();
+    let test = thread::spawn(move || {
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(test));
+        system.stop();
+        if let Err(e) = result {
+            panic::resume_unwind(e);
+        }
+    });
+    sys.run().unwrap();
+    test.join().unwrap();
+}
+
+// Messages go through mailboxes and other threads, so they may take a moment to arrive
+pub fn wait_for<F: Fn() -> bool>(done: F) {
+    for _ in 0..1000 {
+        if done() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("Timed out");
+}
@@ -0,0 +1,81 @@
+// Builds c/storm_test.c against the library and runs it against a fake server
+
+use std::env;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+// Where cargo put the library, the test itself being in deps below it
+fn target_dir() -> PathBuf {
+    let mut dir = env::current_exe().unwrap();
+    dir.pop();
+    if dir.ends_with("deps") {
+        dir.pop();
+    }
+    dir
+}
+
+fn frame(command: &str, payload: &[u8]) -> Vec<u8> {
+    let len = (command.len() + payload.len()) as u16;
+    let mut frame = vec![(len >> 8) as u8, len as u8];
+    frame.extend_from_slice(command.as_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+// Waits for the player's HELO, sets the volume to 50 and switches the player off, then keeps
+// reading until the player goes away
+fn serve(listener: TcpListener) {
+    let (mut stream, _) = listener.accept().unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(20)))
+        .unwrap();
+
+    let mut head = [0u8; 8];
+    stream.read_exact(&mut head).unwrap();
+    assert_eq!(&head[..4], b"HELO");
+    let len = head[4..].iter().fold(0, |len, &b| len << 8 | b as usize);
+    stream.read_exact(&mut vec![0u8; len]).unwrap();
+
+    let mut audg = vec![0u8; 10];
+    audg.extend_from_slice(&[0, 0, 0x80, 0, 0, 0, 0x80, 0]);
+    stream.write_all(&frame("audg", &audg)).unwrap();
+    stream.write_all(&frame("aude", &[0, 0])).unwrap();
+
+    let mut buf = [0u8; 1024];
+    while let Ok(n) = stream.read(&mut buf) {
+        if n == 0 {
+            break;
+        }
+    }
+}
+
+#[test]
+fn c_player_follows_server() {
+    let target = target_dir();
+    let program = target.join("storm_test");
+    let status = Command::new(env::var("CC").unwrap_or_else(|_| String::from("cc")))
+        .args(&["c/storm_test.c", "-Iinclude", "-L"])
+        .arg(&target)
+        .args(&["-lstorm", "-lpthread", "-o"])
+        .arg(&program)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .status()
+        .expect("Unable to run the C compiler");
+    assert!(status.success(), "Unable to build c/storm_test.c");
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server = listener.local_addr().unwrap();
+    let fake = thread::spawn(move || serve(listener));
+
+    let status = Command::new(&program)
+        .arg(server.to_string())
+        .env("LD_LIBRARY_PATH", &target)
+        .status()
+        .unwrap();
+    assert!(status.success(), "storm_test failed");
+    fake.join().unwrap();
+}